@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use itertools::Itertools;
+
+pub(crate) struct Cycle<S> {
+    pub(crate) tail_length: usize,
+    pub(crate) cycle_length: usize,
+    pub(crate) state: S,
+}
+
+pub(crate) fn cycle_index(tail_length: usize, cycle_length: usize, steps: usize) -> usize {
+    if steps < tail_length {
+        steps
+    } else {
+        tail_length + (steps - tail_length) % cycle_length
+    }
+}
+
+// every state visited before the first repeat and the index the repeated state was first seen at,
+// None if no state repeats within `limit` transitions
+pub(crate) fn walk_until_repeat<S: Hash + Eq + Clone>(start: S, mut next: impl FnMut(&S) -> S, limit: usize) -> Option<(Vec<S>, usize)> {
+    let mut history = vec![];
+    let mut known = HashMap::new();
+    let mut current = start;
+    while !known.contains_key(&current) {
+        if history.len() > limit {
+            return None;
+        }
+        known.insert(current.clone(), history.len());
+        history.push(current.clone());
+        current = next(&current);
+    }
+    let tail_length = known[&current];
+    Some((history, tail_length))
+}
+
+// state after `steps` applications of `next`, remembering every visited state
+pub(crate) fn find_cycle_hashed<S: Hash + Eq + Clone>(start: S, next: impl FnMut(&S) -> S, steps: usize) -> Cycle<S> {
+    let (mut history, tail_length) = walk_until_repeat(start, next, usize::MAX).unwrap();
+    let cycle_length = history.len() - tail_length;
+    let index = cycle_index(tail_length, cycle_length, steps);
+    Cycle {
+        tail_length,
+        cycle_length,
+        state: history.swap_remove(index),
+    }
+}
+
+fn advance<S: Clone>(start: &S, next: &impl Fn(&S) -> S, steps: usize) -> S {
+    let mut current = start.clone();
+    for _ in 0..steps {
+        current = next(&current);
+    }
+    current
+}
+
+// Brent's algorithm, keeps only a couple of states at the expense of re-running the transitions
+pub(crate) fn find_cycle_brent<S: Eq + Clone>(start: S, next: impl Fn(&S) -> S, steps: usize) -> Cycle<S> {
+    let mut power = 1;
+    let mut cycle_length = 1;
+    let mut tortoise = start.clone();
+    let mut hare = next(&start);
+    while tortoise != hare {
+        if power == cycle_length {
+            tortoise = hare.clone();
+            power *= 2;
+            cycle_length = 0;
+        }
+        hare = next(&hare);
+        cycle_length += 1;
+    }
+    let mut tail_length = 0;
+    let mut tortoise = start.clone();
+    let mut hare = advance(&start, &next, cycle_length);
+    while tortoise != hare {
+        tortoise = next(&tortoise);
+        hare = next(&hare);
+        tail_length += 1;
+    }
+    let index = cycle_index(tail_length, cycle_length, steps);
+    let state = if index >= tail_length {
+        advance(&tortoise, &next, index - tail_length)
+    } else {
+        advance(&start, &next, index)
+    };
+    Cycle {
+        tail_length,
+        cycle_length,
+        state,
+    }
+}
+
+// steps at which a predicate holds along an eventually periodic sequence of states: the listed ones
+// before `tail_length`, and from there on every `cycle_length` steps at tail_length + offset
+pub(crate) struct Occurrences {
+    tail_length: usize,
+    tail_steps: Vec<usize>,
+    cycle_length: usize,
+    offsets: Vec<usize>,
+}
+
+impl Occurrences {
+    pub(crate) fn new<S>(history: &[S], tail_length: usize, predicate: impl Fn(&S) -> bool) -> Self {
+        let steps = (0..history.len()).filter(|&step| predicate(&history[step])).collect_vec();
+        Self {
+            tail_length,
+            tail_steps: steps.iter().copied().filter(|&step| step < tail_length).collect(),
+            cycle_length: history.len() - tail_length,
+            offsets: steps.iter().filter(|&&step| step >= tail_length).map(|step| step - tail_length).collect(),
+        }
+    }
+
+    pub(crate) fn contains(&self, step: usize) -> bool {
+        if step < self.tail_length {
+            self.tail_steps.contains(&step)
+        } else {
+            self.offsets.contains(&((step - self.tail_length) % self.cycle_length))
+        }
+    }
+}
+
+// first step not before `from` at which every sequence has an occurrence
+pub(crate) fn earliest_common_step(occurrences: &[Occurrences], from: usize) -> Option<usize> {
+    let tail_length = occurrences.iter().map(|o| o.tail_length).max().unwrap_or(0);
+    if let Some(step) = (from..tail_length).find(|&step| occurrences.iter().all(|o| o.contains(step))) {
+        return Some(step);
+    }
+    // past every tail each sequence is purely periodic, so combine the residues with the chinese remainder theorem
+    let mut residues = vec![0i128];
+    let mut modulus = 1i128;
+    for o in occurrences {
+        let cycle_length = o.cycle_length as i128;
+        residues = residues.iter()
+            .cartesian_product(o.offsets.iter().map(|&offset| (o.tail_length + offset) as i128 % cycle_length))
+            .filter_map(|(&a, b)| crt(a, modulus, b, cycle_length))
+            .unique()
+            .collect();
+        modulus = modulus / gcd(modulus, cycle_length) * cycle_length;
+    }
+    let first = from.max(tail_length) as i128;
+    residues.iter()
+        .map(|&residue| residue + ((first - residue).max(0) + modulus - 1) / modulus * modulus)
+        .min()
+        .and_then(|step| usize::try_from(step).ok())
+}
+
+// x = a (mod m) and x = b (mod n) as a single residue modulo lcm(m, n)
+fn crt(a: i128, m: i128, b: i128, n: i128) -> Option<i128> {
+    let g = gcd(m, n);
+    if (b - a) % g != 0 {
+        return None;
+    }
+    let lcm = m / g * n;
+    let k = ((b - a) / g).rem_euclid(n / g) * inverse(m / g, n / g) % (n / g);
+    Some((a + m * k).rem_euclid(lcm))
+}
+
+fn inverse(a: i128, modulus: i128) -> i128 {
+    let (mut old_r, mut r) = (a.rem_euclid(modulus), modulus);
+    let (mut old_s, mut s) = (1i128, 0i128);
+    while r != 0 {
+        let q = old_r / r;
+        (old_r, r) = (r, old_r - q * r);
+        (old_s, s) = (s, old_s - q * s);
+    }
+    old_s.rem_euclid(modulus)
+}
+
+fn gcd(a: i128, b: i128) -> i128 {
+    if b == 0 {
+        return a;
+    }
+    gcd(b, a % b)
+}
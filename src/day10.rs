@@ -2,6 +2,7 @@ use std::collections::{HashMap};
 use std::fs;
 use std::ops::Add;
 use itertools::{Itertools};
+use crate::polygon::Polygon;
 
 #[derive(Eq, PartialEq, Hash, Copy, Clone)]
 struct Coordinates {
@@ -144,24 +145,12 @@ impl Map {
     }
 
     fn find_enclosed(&self) -> i64 {
-        let mut cycle = self.find_cycle();
-        cycle.push(self.starting);
-        let area = shoelace_area(&cycle);
-        // cycle edges are "included" in the area
-        area - (cycle.len() / 2) as i64 + 1
+        let cycle = self.find_cycle();
+        Polygon::new(cycle.iter().map(|c| (c.x, c.y)).collect())
+            .interior_points()
     }
 }
 
-fn shoelace_area(coords: &Vec<Coordinates>) -> i64 {
-    let mut s = 0;
-    for pair in coords.windows(2) {
-        let (c1, c2) = pair.iter().collect_tuple().unwrap();
-        let tmp = (c1.x * c2.y) - (c1.y * c2.x);
-        s += tmp
-    }
-    s.abs() / 2
-}
-
 fn part2(map: &Map) -> i64 {
     map.find_enclosed()
 }
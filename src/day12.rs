@@ -1,5 +1,5 @@
-use std::collections::HashMap;
-use std::fs;
+use std::collections::{HashMap, HashSet};
+use std::{fmt, fs};
 use itertools::Itertools;
 use crate::day12::Spring::{BROKEN, OPERATIONAL, UNKNOWN};
 
@@ -13,12 +13,20 @@ enum Spring {
 impl Spring {
     fn new(c: &char) -> Self {
         match c {
-            '#' => OPERATIONAL,
-            '.' => BROKEN,
+            '#' => BROKEN,
+            '.' => OPERATIONAL,
             '?' => UNKNOWN,
             &_ => panic!()
         }
     }
+
+    fn symbol(&self) -> char {
+        match self {
+            OPERATIONAL => '.',
+            BROKEN => '#',
+            UNKNOWN => '?',
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Hash)]
@@ -28,6 +36,17 @@ struct State {
     position: usize,
 }
 
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+struct UnfoldState {
+    drift: i64,
+    group_size: u8,
+}
+
+struct Unfolding {
+    factor: usize,
+    separator: Spring,
+}
+
 struct Row {
     springs: Vec<Spring>,
     counts: Vec<u8>,
@@ -87,15 +106,15 @@ impl Row {
             }
         } else {
             let current = self.springs.get(position).unwrap();
-            if current == &BROKEN {
+            if current == &OPERATIONAL {
                 let closed_counts = self.closed_group(current_counts, current_group_size);
                 self.caching_arrangements(&closed_counts, 0, position + 1, known)
-            } else if current == &OPERATIONAL {
+            } else if current == &BROKEN {
                 self.caching_arrangements(current_counts, current_group_size + 1, position + 1, known)
             } else {// wildcard
-                // put operational
-                let mut sub_arrangements = self.caching_arrangements(current_counts, current_group_size + 1, position + 1, known);
                 // put broken
+                let mut sub_arrangements = self.caching_arrangements(current_counts, current_group_size + 1, position + 1, known);
+                // put operational
                 let closed_counts = self.closed_group(current_counts, current_group_size);
                 sub_arrangements += self.caching_arrangements(&closed_counts, 0, position + 1, known);
                 sub_arrangements
@@ -103,15 +122,67 @@ impl Row {
         };
     }
 
-    fn expand(&self) -> Row {
+    // picks the arrangement with given index, damaged springs are tried before operational ones
+    fn nth_arrangement(&self, index: u64) -> Option<Vec<Spring>> {
+        let mut known = HashMap::new();
+        let mut remaining = index;
+        let mut arrangement = vec![];
+        let mut current_counts = vec![];
+        let mut current_group_size = 0;
+        if remaining >= self.caching_arrangements(&current_counts, current_group_size, 0, &mut known) {
+            return None;
+        }
+        for (position, spring) in self.springs.iter().enumerate() {
+            let choice = if spring != &UNKNOWN {
+                *spring
+            } else {
+                let broken_arrangements = self.caching_arrangements(&current_counts, current_group_size + 1, position + 1, &mut known);
+                if remaining < broken_arrangements {
+                    BROKEN
+                } else {
+                    remaining -= broken_arrangements;
+                    OPERATIONAL
+                }
+            };
+            if choice == BROKEN {
+                current_group_size += 1;
+            } else {
+                current_counts = self.closed_group(&current_counts, current_group_size);
+                current_group_size = 0;
+            }
+            arrangement.push(choice);
+        }
+        Some(arrangement)
+    }
+
+    fn render(&self, arrangement: &[Spring]) -> String {
+        let counts = self.counts.iter().join(",");
+        let springs: String = self.springs.iter().map(Spring::symbol).collect();
+        let chosen: String = arrangement.iter().map(Spring::symbol).collect();
+        let mut annotations = String::new();
+        let mut group_size = 0;
+        for (position, spring) in arrangement.iter().chain([OPERATIONAL].iter()).enumerate() {
+            if spring == &BROKEN {
+                group_size += 1;
+            } else if group_size > 0 {
+                let start = position - group_size;
+                annotations.push_str(&" ".repeat(start - annotations.len()));
+                annotations.push_str(&group_size.to_string());
+                group_size = 0;
+            }
+        }
+        format!("{} {}\n{} {}\n{}", springs, counts, chosen, counts, annotations)
+    }
+
+    fn unfold(&self, unfolding: &Unfolding) -> Row {
         let mut expanded_springs = vec![];
         let mut expanded_counts = vec![];
-        for i in 0..5 {
+        for i in 0..unfolding.factor {
             for s in &self.springs {
                 expanded_springs.push(*s)
             }
-            if i < 4 {
-                expanded_springs.push(UNKNOWN);
+            if i + 1 < unfolding.factor {
+                expanded_springs.push(unfolding.separator);
             }
             for c in &self.counts {
                 expanded_counts.push(*c)
@@ -122,12 +193,350 @@ impl Row {
             counts: expanded_counts,
         }
     }
+
+    fn expected_count(&self, drift: i64) -> u8 {
+        self.counts[drift.rem_euclid(self.counts.len() as i64) as usize]
+    }
+
+    fn unfold_step(&self, states: &HashMap<UnfoldState, u64>, spring: &Spring, modulus: u64) -> HashMap<UnfoldState, u64> {
+        let mut next = HashMap::new();
+        for (state, &ways) in states {
+            let expected = self.expected_count(state.drift);
+            let mut successors = vec![];
+            if spring != &OPERATIONAL && state.group_size < expected { // put broken
+                successors.push(UnfoldState { drift: state.drift, group_size: state.group_size + 1 });
+            }
+            if spring != &BROKEN { // put operational
+                if state.group_size == 0 {
+                    successors.push(*state);
+                } else if state.group_size == expected {
+                    successors.push(UnfoldState { drift: state.drift + 1, group_size: 0 });
+                }
+            }
+            for successor in successors {
+                let entry = next.entry(successor).or_insert(0);
+                *entry = add_mod(*entry, ways, modulus);
+            }
+        }
+        next
+    }
+
+    fn completed_arrangements(&self, states: &HashMap<UnfoldState, u64>, modulus: u64) -> u64 {
+        let groups = self.counts.len() as i64;
+        states.iter()
+            .filter(|(state, _)| if state.group_size == 0 {
+                state.drift == groups
+            } else {
+                state.group_size == self.expected_count(state.drift) && state.drift + 1 == groups
+            })
+            .map(|(_, &ways)| ways)
+            .fold(0, |acc, ways| add_mod(acc, ways, modulus))
+    }
+
+    // moves the states past the separator and renumbers drift relative to the next copy's groups
+    fn next_copy(&self, states: &HashMap<UnfoldState, u64>, unfolding: &Unfolding, modulus: u64) -> HashMap<UnfoldState, u64> {
+        self.unfold_step(states, &unfolding.separator, modulus)
+            .into_iter()
+            .map(|(state, ways)| (UnfoldState { drift: state.drift - self.counts.len() as i64, group_size: state.group_size }, ways))
+            .collect()
+    }
+
+    // a state at the end of a copy with its drift reduced modulo the groups, and how far that reduction moved it
+    fn reduced(&self, state: &UnfoldState) -> (UnfoldState, i64) {
+        let drift = state.drift.rem_euclid(self.counts.len() as i64);
+        (UnfoldState { drift, group_size: state.group_size }, state.drift - drift)
+    }
+
+    // where one more copy (separator included) takes each reduced state at the end of a copy
+    fn copy_transitions(&self, unfolding: &Unfolding, modulus: u64) -> Transitions {
+        let groups = self.counts.len() as i64;
+        let largest = *self.counts.iter().max().unwrap();
+        (0..groups).cartesian_product(0..=largest)
+            .map(|(drift, group_size)| {
+                let state = UnfoldState { drift, group_size };
+                let mut states = self.next_copy(&HashMap::from([(state, 1)]), unfolding, modulus);
+                for spring in &self.springs {
+                    states = self.unfold_step(&states, spring, modulus);
+                }
+                (state, states.into_iter().collect())
+            })
+            .collect()
+    }
+
+    fn apply_copy(&self, transitions: &Transitions, states: &HashMap<UnfoldState, u64>, modulus: u64) -> HashMap<UnfoldState, u64> {
+        let mut next = HashMap::new();
+        for (state, &ways) in states {
+            let (reduced, shift) = self.reduced(state);
+            for (successor, successor_ways) in &transitions[&reduced] {
+                let entry = next.entry(UnfoldState { drift: successor.drift + shift, group_size: successor.group_size }).or_insert(0);
+                *entry = add_mod(*entry, mul_mod(ways, *successor_ways, modulus), modulus);
+            }
+        }
+        next
+    }
+
+    // drift a state at the end of a copy has to be at for the row to be complete right there
+    fn finishing_drift(&self, state: &UnfoldState) -> Option<i64> {
+        let groups = self.counts.len() as i64;
+        if state.group_size == 0 {
+            Some(groups)
+        } else if state.group_size == self.expected_count(state.drift) {
+            Some(groups - 1)
+        } else {
+            None
+        }
+    }
+
+    // Every state at the end of a copy which is reachable and can still complete the row after some number
+    // of further copies, judged by the range of shifts from the reduced state for which that is possible.
+    // None when there are more than LIVE_STATES_LIMIT of them: if copies can both fall behind and catch up
+    // on the groups the drift is unbounded, e.g. "??? 1" unfolded with '?'.
+    fn live_states(&self, transitions: &Transitions, first: &HashMap<UnfoldState, u64>) -> Option<HashSet<UnfoldState>> {
+        let finishing: HashMap<UnfoldState, i64> = transitions.keys()
+            .filter_map(|state| self.finishing_drift(state).map(|drift| (*state, drift - state.drift)))
+            .collect();
+        let least = self.extreme_drift_changes(transitions, &finishing, 1);
+        let greatest = self.extreme_drift_changes(transitions, &finishing, -1);
+        let alive = |state: &UnfoldState| {
+            let (reduced, shift) = self.reduced(state);
+            match (least.get(&reduced), greatest.get(&reduced)) {
+                (Some(&least), Some(&greatest)) => (least == i64::MIN || least <= shift) && (greatest == i64::MIN || shift <= -greatest),
+                _ => false
+            }
+        };
+        let mut live: HashSet<UnfoldState> = first.keys().copied().filter(|state| alive(state)).collect();
+        let mut queue = live.iter().copied().collect_vec();
+        while let Some(state) = queue.pop() {
+            let (reduced, shift) = self.reduced(&state);
+            for (successor, _) in &transitions[&reduced] {
+                let successor = UnfoldState { drift: successor.drift + shift, group_size: successor.group_size };
+                if alive(&successor) && live.insert(successor) {
+                    if live.len() > LIVE_STATES_LIMIT {
+                        return None;
+                    }
+                    queue.push(successor);
+                }
+            }
+        }
+        Some(live)
+    }
+
+    // Bellman-Ford over the reduced states: the least (with `sign` -1 the negated greatest) shift of a reduced
+    // state's drift after which some sequence of copies ends with a completed row. i64::MIN stands for no bound
+    // and states which can never complete are missing.
+    fn extreme_drift_changes(&self, transitions: &Transitions, finishing: &HashMap<UnfoldState, i64>, sign: i64) -> HashMap<UnfoldState, i64> {
+        let mut best: HashMap<UnfoldState, i64> = finishing.iter().map(|(state, &change)| (*state, sign * change)).collect();
+        let rounds = transitions.len();
+        for round in 0..2 * rounds {
+            let mut changed = false;
+            for (state, successors) in transitions {
+                for (successor, _) in successors {
+                    let (reduced, shift) = self.reduced(successor);
+                    let Some(&rest) = best.get(&reduced) else { continue };
+                    let candidate = if rest == i64::MIN { i64::MIN } else { rest - sign * shift };
+                    if best.get(state).is_none_or(|&current| candidate < current) {
+                        // still improving after as many rounds as there are states means a cycle which keeps improving
+                        best.insert(*state, if round >= rounds { i64::MIN } else { candidate });
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        best
+    }
+
+    // Counts arrangements of the unfolded row modulo a prime without materializing it. When the states at the
+    // ends of copies which can still lead to a completed row form a finite set, the counts follow one transfer
+    // matrix over that set, so they satisfy a linear recurrence no longer than the set is large, and
+    // Berlekamp-Massey on twice as many terms finds it exactly. Otherwise only factors up to COUNTING_LIMIT
+    // are counted copy by copy.
+    fn unfolded_arrangements_modulo(&self, unfolding: &Unfolding, modulus: u64) -> Result<u64, UnfoldError> {
+        if unfolding.factor == 0 {
+            return Ok(1 % modulus);
+        }
+        let mut states = HashMap::from([(UnfoldState { drift: 0, group_size: 0 }, 1)]);
+        for spring in &self.springs {
+            states = self.unfold_step(&states, spring, modulus);
+        }
+        let transitions = self.copy_transitions(unfolding, modulus);
+        let Some(live) = self.live_states(&transitions, &states) else {
+            return if unfolding.factor <= COUNTING_LIMIT {
+                Ok(self.counted_arrangements(unfolding, modulus))
+            } else {
+                Err(UnfoldError::UnboundedDrift { factor: unfolding.factor, limit: COUNTING_LIMIT })
+            };
+        };
+        let mut terms = vec![];
+        loop {
+            states.retain(|state, _| live.contains(state));
+            terms.push(self.completed_arrangements(&states, modulus));
+            if terms.len() == unfolding.factor {
+                return Ok(*terms.last().unwrap());
+            }
+            if terms.len() == 2 * live.len() {
+                let recurrence = berlekamp_massey(&terms, modulus);
+                return Ok(linear_recurrence_term(&terms, &recurrence, unfolding.factor - 1, modulus));
+            }
+            states = self.apply_copy(&transitions, &states, modulus);
+        }
+    }
+
+    fn counted_arrangements(&self, unfolding: &Unfolding, modulus: u64) -> u64 {
+        let groups = self.counts.len() as i64;
+        let mut states = HashMap::from([(UnfoldState { drift: 0, group_size: 0 }, 1)]);
+        for copy in 0..unfolding.factor {
+            if copy > 0 {
+                states = self.next_copy(&states, unfolding, modulus);
+            }
+            for spring in &self.springs {
+                states = self.unfold_step(&states, spring, modulus);
+            }
+            // states which already passed all the groups of the unfolded row can never complete
+            let copies_left = (unfolding.factor - copy) as i64;
+            states.retain(|state, _| state.drift <= copies_left * groups);
+        }
+        self.completed_arrangements(&states, modulus)
+    }
+}
+
+// states at the end of a copy, with drift reduced modulo the groups, to the states one copy later and the ways to get there
+type Transitions = HashMap<UnfoldState, Vec<(UnfoldState, u64)>>;
+
+#[derive(Debug)]
+enum UnfoldError {
+    UnboundedDrift { factor: usize, limit: usize },
+}
+
+impl fmt::Display for UnfoldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnfoldError::UnboundedDrift { factor, limit } =>
+                write!(f, "copies can fall behind and catch up on groups without bound, so only factors up to {} are counted, not {}", limit, factor),
+        }
+    }
+}
+
+// largest prime below 2^64, counts smaller than it come back exactly
+const MODULUS: u64 = 18446744073709551557;
+const LIVE_STATES_LIMIT: usize = 4096;
+const COUNTING_LIMIT: usize = 256;
+
+fn add_mod(a: u64, b: u64, modulus: u64) -> u64 {
+    ((a as u128 + b as u128) % modulus as u128) as u64
+}
+
+fn mul_mod(a: u64, b: u64, modulus: u64) -> u64 {
+    ((a as u128 * b as u128) % modulus as u128) as u64
+}
+
+fn pow_mod(base: u64, exponent: u64, modulus: u64) -> u64 {
+    let mut result = 1 % modulus;
+    let mut base = base % modulus;
+    let mut exponent = exponent;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul_mod(result, base, modulus);
+        }
+        base = mul_mod(base, base, modulus);
+        exponent >>= 1;
+    }
+    result
 }
 
-fn part2(rows: &Vec<Row>) -> u64 {
+// shortest c such that terms[i] = sum(c[j] * terms[i - 1 - j]), modulus has to be prime
+fn berlekamp_massey(terms: &[u64], modulus: u64) -> Vec<u64> {
+    let mut current: Vec<u64> = vec![];
+    let mut last: Vec<u64> = vec![];
+    let mut last_failure = 0;
+    let mut last_delta = 0;
+    for i in 0..terms.len() {
+        let predicted = current.iter().enumerate()
+            .fold(0, |acc, (j, &c)| add_mod(acc, mul_mod(c, terms[i - 1 - j], modulus), modulus));
+        let delta = add_mod(terms[i], modulus - predicted, modulus);
+        if delta == 0 {
+            continue;
+        }
+        if current.is_empty() {
+            current = vec![0; i + 1];
+            last_failure = i;
+            last_delta = delta;
+            continue;
+        }
+        let scale = mul_mod(delta, pow_mod(last_delta, modulus - 2, modulus), modulus);
+        let mut corrected = vec![0; i - last_failure - 1];
+        corrected.push(scale);
+        for &c in &last {
+            corrected.push(mul_mod(modulus - c, scale, modulus));
+        }
+        if corrected.len() < current.len() {
+            corrected.resize(current.len(), 0);
+        }
+        for (j, &c) in current.iter().enumerate() {
+            corrected[j] = add_mod(corrected[j], c, modulus);
+        }
+        if i - last_failure + last.len() >= current.len() {
+            last = current;
+            last_failure = i;
+            last_delta = delta;
+        }
+        current = corrected;
+    }
+    current
+}
+
+fn poly_mul_mod(a: &[u64], b: &[u64], recurrence: &[u64], modulus: u64) -> Vec<u64> {
+    let order = recurrence.len();
+    let mut product = vec![0; 2 * order];
+    for (i, &x) in a.iter().enumerate() {
+        for (j, &y) in b.iter().enumerate() {
+            product[i + j] = add_mod(product[i + j], mul_mod(x, y, modulus), modulus);
+        }
+    }
+    for i in (order..2 * order).rev() { // x^order = sum(c[j] * x^(order - 1 - j))
+        let coefficient = product[i];
+        for (j, &c) in recurrence.iter().enumerate() {
+            product[i - 1 - j] = add_mod(product[i - 1 - j], mul_mod(coefficient, c, modulus), modulus);
+        }
+    }
+    product.truncate(order);
+    product
+}
+
+fn linear_recurrence_term(terms: &[u64], recurrence: &[u64], index: usize, modulus: u64) -> u64 {
+    let order = recurrence.len();
+    if order == 0 {
+        return 0;
+    }
+    if index < terms.len() {
+        return terms[index];
+    }
+    let mut result = vec![0; order];
+    result[0] = 1 % modulus;
+    let mut base = vec![0; order];
+    if order == 1 {
+        base[0] = recurrence[0];
+    } else {
+        base[1] = 1;
+    }
+    let mut exponent = index;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = poly_mul_mod(&result, &base, recurrence, modulus);
+        }
+        base = poly_mul_mod(&base, &base, recurrence, modulus);
+        exponent >>= 1;
+    }
+    result.iter().enumerate()
+        .fold(0, |acc, (i, &r)| add_mod(acc, mul_mod(r, terms[i], modulus), modulus))
+}
+
+// five copies of a row have far fewer arrangements than MODULUS, so the counts are exact
+fn part2(rows: &Vec<Row>) -> Result<u64, UnfoldError> {
     rows.iter()
-        .map(Row::expand)
-        .map(|r| r.count_arrangements())
+        .map(|r| r.unfolded_arrangements_modulo(&Unfolding { factor: 5, separator: UNKNOWN }, MODULUS))
         .sum()
 }
 
@@ -143,5 +552,81 @@ pub(crate) fn solve() {
         .map(Row::new)
         .collect();
     println!("{}", part1(&rows));
-    println!("{}", part2(&rows));
-}
\ No newline at end of file
+    match part2(&rows) {
+        Ok(arrangements) => println!("{}", arrangements),
+        Err(error) => println!("{}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "???.### 1,1,3\n.??..??...?##. 1,1,3\n?#?#?#?#?#?#?#? 1,3,1,6\n????.#...#... 4,1,1\n????.######..#####. 1,6,5\n?###???????? 3,2,1";
+
+    #[test]
+    fn unfolding_matches_expanded_rows() {
+        let mut seed = 7u64;
+        let mut below = |n: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+        for _ in 0..100 {
+            let springs: String = (0..1 + below(6)).map(|_| ['#', '.', '?', '?'][below(4) as usize]).collect();
+            let counts = (0..1 + below(3)).map(|_| 1 + below(3)).join(",");
+            let row = Row::new(&format!("{} {}", springs, counts));
+            for separator in [UNKNOWN, BROKEN, OPERATIONAL] {
+                for factor in 1..=5 {
+                    let unfolding = Unfolding { factor, separator };
+                    assert_eq!(row.unfolded_arrangements_modulo(&unfolding, MODULUS).unwrap(), row.unfold(&unfolding).count_arrangements(),
+                               "{} {} joined by {}, factor {}", springs, counts, separator.symbol(), factor);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn large_factors_match_counting_copy_by_copy() {
+        for row in SAMPLE.lines().map(Row::new) {
+            let unfolding = Unfolding { factor: 200, separator: UNKNOWN };
+            assert_eq!(row.unfolded_arrangements_modulo(&unfolding, 1_000_000_007).unwrap(), row.counted_arrangements(&unfolding, 1_000_000_007));
+            assert!(row.unfolded_arrangements_modulo(&Unfolding { factor: 1_000_000, separator: UNKNOWN }, MODULUS).is_ok());
+        }
+    }
+
+    #[test]
+    fn unbounded_drift_is_only_counted_for_small_factors() {
+        let row = Row::new("??? 1");
+        // all 4n - 1 springs unknown, n single broken springs: C(3n, n) arrangements
+        assert_eq!(row.unfolded_arrangements_modulo(&Unfolding { factor: 10, separator: UNKNOWN }, MODULUS).unwrap(), 30045015);
+        assert!(row.unfolded_arrangements_modulo(&Unfolding { factor: COUNTING_LIMIT, separator: UNKNOWN }, MODULUS).is_ok());
+        assert!(row.unfolded_arrangements_modulo(&Unfolding { factor: 1_000_000, separator: UNKNOWN }, MODULUS).is_err());
+    }
+
+    #[test]
+    fn arrangements_are_listed_once_each() {
+        for row in SAMPLE.lines().map(Row::new) {
+            let count = row.count_arrangements();
+            let arrangements = (0..count).map(|index| row.nth_arrangement(index).unwrap()).collect_vec();
+            assert_eq!(arrangements.iter().unique().count() as u64, count);
+            assert!(row.nth_arrangement(count).is_none());
+            for arrangement in &arrangements {
+                assert!(row.springs.iter().zip(arrangement).all(|(spring, chosen)| spring == &UNKNOWN || spring == chosen));
+                let groups = arrangement.split(|spring| spring != &BROKEN).filter(|group| !group.is_empty()).map(|group| group.len() as u8).collect_vec();
+                assert_eq!(groups, row.counts);
+            }
+        }
+    }
+
+    #[test]
+    fn render_annotates_groups() {
+        let row = Row::new("???.### 1,1,3");
+        assert_eq!(row.render(&row.nth_arrangement(0).unwrap()), "???.### 1,1,3\n#.#.### 1,1,3\n1 1 3");
+    }
+
+    #[test]
+    fn sample_part2() {
+        let rows = SAMPLE.lines().map(Row::new).collect();
+        assert_eq!(part2(&rows).unwrap(), 525152);
+    }
+}
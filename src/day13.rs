@@ -1,3 +1,4 @@
+use std::fmt;
 use std::fs;
 use itertools::Itertools;
 use crate::day13::Terrain::{ASH, ROCK};
@@ -16,117 +17,244 @@ impl Terrain {
             &_ => panic!()
         }
     }
-    fn opposite(&self) -> Self {
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum Orientation {
+    Vertical,
+    Horizontal,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+struct Reflection {
+    orientation: Orientation,
+    index: usize,
+}
+
+impl Reflection {
+    fn summary(&self) -> usize {
+        match self.orientation {
+            Orientation::Vertical => self.index,
+            Orientation::Horizontal => self.index * 100
+        }
+    }
+}
+
+impl fmt::Display for Reflection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.orientation {
+            Orientation::Vertical => write!(f, "vertical line after column {}", self.index),
+            Orientation::Horizontal => write!(f, "horizontal line after row {}", self.index)
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ReflectionError {
+    NoReflection { smudges: u32 },
+    MultipleReflections { smudges: u32, reflections: Vec<Reflection> },
+}
+
+impl fmt::Display for ReflectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ASH => ROCK,
-            ROCK => ASH
+            ReflectionError::NoReflection { smudges } =>
+                write!(f, "no reflection with exactly {} smudge(s)", smudges),
+            ReflectionError::MultipleReflections { smudges, reflections } =>
+                write!(f, "{} reflections with exactly {} smudge(s): {}", reflections.len(), smudges, reflections.iter().join(", "))
         }
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+struct Cell {
+    row: usize,
+    column: usize,
+}
+
+// a pair of cells mirrored by a candidate line which differ, flipping either of them removes the mismatch
+struct Mismatch {
+    cell: Cell,
+    mirror: Cell,
+}
+
+struct Candidate {
+    reflection: Reflection,
+    mismatches: Vec<Mismatch>,
+}
+
+// bitset of rock cells along a row or column, 64 cells per word
+type Line = Vec<u64>;
+
 struct Pattern {
-    rows: Vec<Vec<Terrain>>,
-    columns: Vec<Vec<Terrain>>,
+    rows: Vec<Line>,
+    columns: Vec<Line>,
 }
 
 impl Pattern {
     fn new(data: &str) -> Self {
-        let mut rows = vec![];
         let column_count = data.lines().find_or_first(|_| true).map(|line| line.len()).unwrap();
         let row_count = data.lines().count();
-        let mut columns: Vec<Vec<Terrain>> = (0..column_count)
-            .map(|_| vec![ASH; row_count])
-            .collect();
-
+        let mut rows = vec![vec![0; column_count.div_ceil(64)]; row_count];
+        let mut columns = vec![vec![0; row_count.div_ceil(64)]; column_count];
         for (row_index, data_row) in data.lines().enumerate() {
-            let mut row = vec![];
             for (column_index, c) in data_row.chars().enumerate() {
-                let t = Terrain::new(&c);
-                row.push(t);
-                columns[column_index][row_index] = t;
+                if Terrain::new(&c) == ROCK {
+                    rows[row_index][column_index / 64] |= 1 << (column_index % 64);
+                    columns[column_index][row_index / 64] |= 1 << (row_index % 64);
+                }
             }
-            rows.push(row);
         }
-
         Pattern {
             rows,
             columns,
         }
     }
 
-    fn find_splits(&self, input: &Vec<Vec<Terrain>>) -> Vec<usize> {
-        self.find_potential_split_starts(input)
-            .iter()
-            .filter(|&&index| self.verify_split(input, index))
-            .map(|x| *x)
-            .collect()
+    // number of cells which differ from their mirror image, with the line placed after `position` lines
+    fn mismatches(&self, input: &[Line], position: usize) -> u32 {
+        let left = input[0..position].iter().rev();
+        let right = &input[position..];
+        left.zip(right)
+            .map(|(a, b)| a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum::<u32>())
+            .sum()
     }
 
-    fn find_potential_split_starts(&self, input: &Vec<Vec<Terrain>>) -> Vec<usize> {
-        input.iter()
-            .tuple_windows()
-            .enumerate()
-            .filter(|(_, (a, b))| a.eq(b))
-            .map(|(index, _)| index)
+    fn find_splits(&self, input: &[Line], smudges: u32) -> Vec<usize> {
+        (1..input.len())
+            .filter(|&position| self.mismatches(input, position) == smudges)
             .collect()
     }
 
-    fn verify_split(&self, input: &Vec<Vec<Terrain>>, position: usize) -> bool {
-        let left = input.as_slice()[0..position + 1].iter().rev();
-        let right = &input.as_slice()[position + 1..input.len()];
-        left.zip(right)
-            .all(|(a, b)| a.eq(b))
-    }
-
-    fn reflections(&self) -> Vec<usize> {
-        let v: Vec<usize> = self.find_splits(&self.columns).iter().map(|x| x + 1).collect();
-        let h: Vec<usize> = self.find_splits(&self.rows).iter().map(|x| (x + 1) * 100).collect();
-        [v, h].concat()
-    }
-
-    fn smudges(&self) -> usize {
-        let original_reflection = *self.reflections().first().unwrap();
-        for (row_index, row) in self.rows.iter().enumerate() {
-            for (col_index, terrain) in row.iter().enumerate() {
-                let mut new_rows = self.rows.clone();
-                let mut new_cols = self.columns.clone();
-                new_rows[row_index][col_index] = terrain.opposite();
-                new_cols[col_index][row_index] = terrain.opposite();
-                let smudge_reflections = Pattern {
-                    rows: new_rows,
-                    columns: new_cols,
-                }.reflections();
-                let new_reflection = smudge_reflections
-                    .iter()
-                    .find(|&&x| x != original_reflection);
-                if new_reflection.is_some() {
-                    return *new_reflection.unwrap();
-                }
+    fn reflections_with_smudges(&self, smudges: u32) -> Vec<Reflection> {
+        let v = self.find_splits(&self.columns, smudges).into_iter()
+            .map(|index| Reflection { orientation: Orientation::Vertical, index });
+        let h = self.find_splits(&self.rows, smudges).into_iter()
+            .map(|index| Reflection { orientation: Orientation::Horizontal, index });
+        v.chain(h).collect()
+    }
+
+    fn reflections(&self) -> Vec<Reflection> {
+        self.reflections_with_smudges(0)
+    }
+
+    fn unique_reflection(&self, smudges: u32) -> Result<Reflection, ReflectionError> {
+        let reflections = self.reflections_with_smudges(smudges);
+        match reflections.len() {
+            0 => Err(ReflectionError::NoReflection { smudges }),
+            1 => Ok(reflections[0]),
+            _ => Err(ReflectionError::MultipleReflections { smudges, reflections })
+        }
+    }
+
+    fn mismatched_cells(&self, reflection: &Reflection) -> Vec<Mismatch> {
+        let input = match reflection.orientation {
+            Orientation::Vertical => &self.columns,
+            Orientation::Horizontal => &self.rows
+        };
+        let mut mismatches = vec![];
+        for (a, b) in (0..reflection.index).rev().zip(reflection.index..input.len()) {
+            let differences = input[a].iter().zip(&input[b]).map(|(x, y)| x ^ y).collect_vec();
+            for bit in (0..differences.len() * 64).filter(|bit| differences[bit / 64] & (1 << (bit % 64)) != 0) {
+                let (cell, mirror) = match reflection.orientation {
+                    Orientation::Vertical => (Cell { row: bit, column: a }, Cell { row: bit, column: b }),
+                    Orientation::Horizontal => (Cell { row: a, column: bit }, Cell { row: b, column: bit })
+                };
+                mismatches.push(Mismatch { cell, mirror });
             }
         }
-        panic!()
+        mismatches
+    }
+
+    fn candidates(&self) -> Vec<Candidate> {
+        let v = (1..self.columns.len())
+            .map(|index| Reflection { orientation: Orientation::Vertical, index });
+        let h = (1..self.rows.len())
+            .map(|index| Reflection { orientation: Orientation::Horizontal, index });
+        v.chain(h)
+            .map(|reflection| Candidate { reflection, mismatches: self.mismatched_cells(&reflection) })
+            .collect()
+    }
+
+    fn report(&self, smudges: u32) -> String {
+        let mut lines = vec![];
+        for candidate in self.candidates() {
+            let cells = candidate.mismatches.iter()
+                .map(|m| format!("({}, {}) <-> ({}, {})", m.cell.row, m.cell.column, m.mirror.row, m.mirror.column))
+                .join(" ");
+            lines.push(format!("{}: {} mismatch(es) {}", candidate.reflection, candidate.mismatches.len(), cells).trim_end().to_string());
+        }
+        match self.unique_reflection(smudges) {
+            Ok(reflection) => lines.push(format!("reflection with {} smudge(s): {}", smudges, reflection)),
+            Err(error) => lines.push(format!("error: {}", error))
+        }
+        lines.join("\n")
     }
 }
 
-fn part2(patterns: &Vec<Pattern>) -> usize {
+fn part2(patterns: &Vec<Pattern>) -> Result<usize, ReflectionError> {
     patterns
         .iter()
-        .map(|p| p.smudges())
+        .map(|p| p.unique_reflection(1).map(|reflection| reflection.summary()))
         .sum()
 }
 
-fn part1(patterns: &Vec<Pattern>) -> usize {
+fn part1(patterns: &Vec<Pattern>) -> Result<usize, ReflectionError> {
     patterns
         .iter()
-        .map(|p| *p.reflections().first().unwrap())
+        .map(|p| p.unique_reflection(0).map(|reflection| reflection.summary()))
         .sum()
 }
 
+// prints the answer, or the report of every pattern without a unique reflection
+fn print_summary(patterns: &[Pattern], smudges: u32, summary: Result<usize, ReflectionError>) {
+    match summary {
+        Ok(summary) => println!("{}", summary),
+        Err(_) => patterns.iter()
+            .enumerate()
+            .filter(|(_, pattern)| pattern.unique_reflection(smudges).is_err())
+            .for_each(|(index, pattern)| println!("pattern {}:\n{}", index + 1, pattern.report(smudges)))
+    }
+}
+
 pub(crate) fn solve() {
     let contents = fs::read_to_string("13.txt").unwrap();
-    let patterns = contents.split("\n\n")
+    let patterns: Vec<Pattern> = contents.split("\n\n")
         .map(|data| Pattern::new(data))
         .collect();
-    println!("{}", part1(&patterns));
-    println!("{}", part2(&patterns));
-}
\ No newline at end of file
+    print_summary(&patterns, 0, part1(&patterns));
+    print_summary(&patterns, 1, part2(&patterns));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "#.##..##.\n..#.##.#.\n##......#\n##......#\n..#.##.#.\n..##..##.\n#.#.##.#.\n\n#...##..#\n#....#..#\n..##..###\n#####.##.\n#####.##.\n..##..###\n#....#..#";
+
+    #[test]
+    fn sample() {
+        let patterns = SAMPLE.split("\n\n").map(Pattern::new).collect();
+        assert_eq!(part1(&patterns).unwrap(), 405);
+        assert_eq!(part2(&patterns).unwrap(), 400);
+    }
+
+    #[test]
+    fn pattern_without_reflection() {
+        let patterns = vec![Pattern::new("#.\n..")];
+        assert!(matches!(part1(&patterns), Err(ReflectionError::NoReflection { smudges: 0 })));
+        assert_eq!(patterns[0].report(0), "vertical line after column 1: 1 mismatch(es) (0, 0) <-> (0, 1)\n\
+                                            horizontal line after row 1: 1 mismatch(es) (0, 0) <-> (1, 0)\n\
+                                            error: no reflection with exactly 0 smudge(s)");
+    }
+
+    #[test]
+    fn pattern_with_two_reflections() {
+        let patterns = vec![Pattern::new("..\n..")];
+        let Err(ReflectionError::MultipleReflections { reflections, .. }) = part1(&patterns) else { panic!("expected two reflections") };
+        assert_eq!(reflections, vec![Reflection { orientation: Orientation::Vertical, index: 1 }, Reflection { orientation: Orientation::Horizontal, index: 1 }]);
+        assert_eq!(patterns[0].report(0), "vertical line after column 1: 0 mismatch(es)\n\
+                                            horizontal line after row 1: 0 mismatch(es)\n\
+                                            error: 2 reflections with exactly 0 smudge(s): vertical line after column 1, horizontal line after row 1");
+    }
+}
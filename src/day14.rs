@@ -1,7 +1,6 @@
-use std::collections::{HashMap};
 use std::fs;
-use std::ops::Add;
 use itertools::Itertools;
+use crate::cycles::find_cycle_hashed;
 use crate::day14::Rock::{Cube, Empty, Rounded};
 
 #[derive(PartialEq, Eq, Hash, Ord, PartialOrd, Clone, Copy)]
@@ -20,27 +19,12 @@ impl Rock {
             &_ => panic!()
         }
     }
-}
-
-#[derive(PartialEq, Eq, Hash, Ord, PartialOrd, Clone, Copy)]
-struct Coord {
-    x: i64,
-    y: i64,
-}
-
-impl Coord {
-    fn in_bounds(&self, max_x: usize, max_y: usize) -> bool {
-        self.x >= 0 && self.x < max_x as i64 && self.y >= 0 && self.y < max_y as i64
-    }
-}
 
-impl Add for Coord {
-    type Output = Self;
-
-    fn add(self, rhs: Self) -> Self::Output {
-        Self {
-            x: self.x + rhs.x,
-            y: self.y + rhs.y,
+    fn symbol(&self) -> char {
+        match self {
+            Empty => '.',
+            Cube => '#',
+            Rounded => 'O',
         }
     }
 }
@@ -53,42 +37,69 @@ enum Direction {
 }
 
 impl Direction {
-    fn vector(&self) -> Coord {
+    fn new(c: &char) -> Self {
+        match c {
+            'N' => Direction::N,
+            'S' => Direction::S,
+            'E' => Direction::E,
+            'W' => Direction::W,
+            &_ => panic!()
+        }
+    }
+
+    fn symbol(&self) -> char {
         match self {
-            Direction::N => Coord { x: 0, y: -1 },
-            Direction::S => Coord { x: 0, y: 1 },
-            Direction::E => Coord { x: 1, y: 0 },
-            Direction::W => Coord { x: -1, y: 0 },
+            Direction::N => 'N',
+            Direction::S => 'S',
+            Direction::E => 'E',
+            Direction::W => 'W',
         }
     }
 
-    fn ranges(&self, max_x: usize, max_y: usize) -> Vec<Coord> {
+    // rocks roll along lanes (columns for N/S, rows for E/W) towards step 0 of the lane
+    fn lanes(&self, width: usize, height: usize) -> (usize, usize) {
         match self {
-            Direction::N => (0..max_y).map(|y| (0..max_x).map(move |x| Coord { x: x as i64, y: y as i64 })).flatten().collect(),
-            Direction::S => (0..max_y).rev().map(|y| (0..max_x).map(move |x| Coord { x: x as i64, y: y as i64 })).flatten().collect(),
-            Direction::E => (0..max_x).map(|x| (0..max_y).map(move |y| Coord { x: x as i64, y: y as i64 })).flatten().collect(),
-            Direction::W => (0..max_x).rev().map(|x| (0..max_y).map(move |y| Coord { x: x as i64, y: y as i64 })).flatten().collect(),
+            Direction::N | Direction::S => (width, height),
+            Direction::E | Direction::W => (height, width),
+        }
+    }
+
+    fn cell(&self, lane: usize, step: usize, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            Direction::N => (lane, step),
+            Direction::S => (lane, height - 1 - step),
+            Direction::W => (step, lane),
+            Direction::E => (width - 1 - step, lane),
         }
     }
 }
 
+struct TiltProgram {
+    directions: Vec<Direction>,
+}
+
+impl TiltProgram {
+    fn new(program: &str) -> Self {
+        TiltProgram {
+            directions: program.trim().chars().map(|c| Direction::new(&c)).collect()
+        }
+    }
+}
+
+#[derive(Clone)]
 struct Platform {
-    rocks: HashMap<Coord, Rock>,
+    rocks: Vec<Vec<Rock>>,
     height: usize,
     width: usize,
 }
 
 impl Platform {
     fn new(data: &str) -> Self {
-        let mut rocks = HashMap::new();
-        let height = data.lines().count();
-        let mut width = 0;
-        for (y, line) in data.lines().enumerate() {
-            width = line.len();
-            for (x, c) in line.chars().enumerate() {
-                rocks.insert(Coord { x: x as i64, y: y as i64 }, Rock::new(&c));
-            }
-        }
+        let rocks: Vec<Vec<Rock>> = data.lines()
+            .map(|line| line.chars().map(|c| Rock::new(&c)).collect())
+            .collect();
+        let height = rocks.len();
+        let width = rocks.first().map(|row| row.len()).unwrap_or(0);
         Platform {
             rocks,
             width,
@@ -96,94 +107,115 @@ impl Platform {
         }
     }
 
-    fn tilt_step(&self, direction: &Direction) -> (bool, Self) {
-        let mut new_rocks = self.rocks.clone();
-        let mut modified = false;
-        for pos in direction.ranges(self.width, self.height) {
-            let &current_rock = new_rocks.get(&pos).unwrap();
-            match current_rock {
-                Rounded => {
-                    let new_pos = pos + direction.vector();
-                    if new_pos.in_bounds(self.width, self.height) {
-                        let destination_rock = new_rocks.get(&new_pos).unwrap();
-                        match destination_rock {
-                            Rounded => {}
-                            Cube => {}
-                            Empty => {
-                                new_rocks.insert(new_pos, current_rock.clone());
-                                new_rocks.insert(pos, Empty);
-                                modified = true;
-                            }
-                        }
+    fn tilt(&self, direction: &Direction) -> Self {
+        let mut rocks = self.rocks.clone();
+        let (lanes, lane_length) = direction.lanes(self.width, self.height);
+        for lane in 0..lanes {
+            let mut free = 0;
+            for step in 0..lane_length {
+                let (x, y) = direction.cell(lane, step, self.width, self.height);
+                match rocks[y][x] {
+                    Rounded => {
+                        let (free_x, free_y) = direction.cell(lane, free, self.width, self.height);
+                        rocks[y][x] = Empty;
+                        rocks[free_y][free_x] = Rounded;
+                        free += 1;
                     }
+                    Cube => free = step + 1,
+                    Empty => {}
                 }
-                Cube => {}
-                Empty => {}
             }
         }
-        (modified, Platform {
-            rocks: new_rocks,
+        Platform {
+            rocks,
             width: self.width,
             height: self.height,
-        })
+        }
     }
 
-    fn tilt_far(&self, direction: &Direction) -> Self {
-        let (_, mut current) = self.tilt_step(direction);
-        loop {
-            let (modified, new_one) = current.tilt_step(direction);
-            current = new_one;
-            if !modified {
-                return current;
-            }
+    fn run_program_traced(&self, program: &TiltProgram, on_step: &mut impl FnMut(&Direction, &Self)) -> Self {
+        let mut current = self.clone();
+        for direction in &program.directions {
+            current = current.tilt(direction);
+            on_step(direction, &current);
         }
+        current
     }
 
-    fn tilt_cycle(&self) -> Self {
-        let mut current = self.tilt_far(&Direction::N);
-        current = current.tilt_far(&Direction::W);
-        current = current.tilt_far(&Direction::S);
-        current = current.tilt_far(&Direction::E);
-        current
+    fn run_program(&self, program: &TiltProgram) -> Self {
+        self.run_program_traced(program, &mut |_, _| {})
     }
 
-    fn hashable(&self) -> Vec<(Coord, Rock)> {
-        self.rocks.iter().map(|(&c, &r)| (c, r)).sorted().collect()
+    fn render(&self) -> String {
+        self.rocks.iter()
+            .map(|row| row.iter().map(Rock::symbol).collect::<String>())
+            .join("\n")
     }
 
-    fn tilt_cycles(&self, steps: usize) -> Self {
-        let mut history = vec![];
-        let mut known = HashMap::new();
-        let mut current = self.tilt_cycle();
-        history.push(current.hashable());
-        known.insert(current.hashable(), 0);
-        let mut cycle_counter = 1;
-        loop {
-            current = current.tilt_cycle();
-            if known.contains_key(&current.hashable()) {
-                break;
+    fn render_program(&self, program: &TiltProgram) -> String {
+        let mut frames = vec![self.render()];
+        self.run_program_traced(program, &mut |direction, platform| {
+            frames.push(format!("{}\n{}", direction.symbol(), platform.render()));
+        });
+        frames.join("\n\n")
+    }
+
+    // bitset of rounded rocks, row by row, cubes never move so this identifies the platform
+    fn hashable(&self) -> Vec<u64> {
+        let mut bits = vec![0; (self.width * self.height).div_ceil(64)];
+        for (y, row) in self.rocks.iter().enumerate() {
+            for (x, rock) in row.iter().enumerate() {
+                if rock == &Rounded {
+                    let index = y * self.width + x;
+                    bits[index / 64] |= 1 << (index % 64);
+                }
             }
-            history.push(current.hashable());
-            known.insert(current.hashable(), cycle_counter);
-            cycle_counter += 1;
         }
-        let cycle_start = known.get(&current.hashable()).unwrap();
-        let cycle_length = cycle_counter - cycle_start;
-        let offset = (steps - cycle_start) % cycle_length;
-        Self {
-            rocks: history[offset + cycle_start - 1].iter().map(|(c, r)| (*c, *r)).collect(),
+        bits
+    }
+
+    fn with_rounded(&self, bits: &[u64]) -> Self {
+        let rocks = self.rocks.iter().enumerate()
+            .map(|(y, row)| row.iter().enumerate()
+                .map(|(x, rock)| {
+                    let index = y * self.width + x;
+                    if rock == &Cube {
+                        Cube
+                    } else if bits[index / 64] & (1 << (index % 64)) != 0 {
+                        Rounded
+                    } else {
+                        Empty
+                    }
+                })
+                .collect())
+            .collect();
+        Platform {
+            rocks,
             height: self.height,
             width: self.width,
         }
     }
 
-    fn north_support(&self) -> usize {
+    fn run_program_repeated(&self, program: &TiltProgram, steps: usize) -> Self {
+        let cycle = find_cycle_hashed(self.hashable(), |bits| self.with_rounded(bits).run_program(program).hashable(), steps);
+        self.with_rounded(&cycle.state)
+    }
+
+    // every rounded rock contributes its distance from the edge opposite to the supporting one
+    fn load(&self, edge: &Direction) -> usize {
         let mut sum = 0;
-        for (coord, rock) in &self.rocks {
-            match rock {
-                Rounded => sum += self.height - coord.y as usize,
-                Cube => {}
-                Empty => {}
+        for (y, row) in self.rocks.iter().enumerate() {
+            for (x, rock) in row.iter().enumerate() {
+                match rock {
+                    Rounded => sum += match edge {
+                        Direction::N => self.height - y,
+                        Direction::S => y + 1,
+                        Direction::W => self.width - x,
+                        Direction::E => x + 1,
+                    },
+                    Cube => {}
+                    Empty => {}
+                }
             }
         }
         sum
@@ -191,13 +223,13 @@ impl Platform {
 }
 
 fn part2(platform: &Platform) -> usize {
-    platform.tilt_cycles(1000000000)
-        .north_support()
+    platform.run_program_repeated(&TiltProgram::new("NWSE"), 1000000000)
+        .load(&Direction::N)
 }
 
 fn part1(platform: &Platform) -> usize {
-    platform.tilt_far(&Direction::N)
-        .north_support()
+    platform.tilt(&Direction::N)
+        .load(&Direction::N)
 }
 
 pub(crate) fn solve() {
@@ -205,4 +237,24 @@ pub(crate) fn solve() {
     let platform = Platform::new(&contents);
     println!("{}", part1(&platform));
     println!("{}", part2(&platform));
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "O....#....\nO.OO#....#\n.....##...\nOO.#O....O\n.O.....O#.\nO.#..O.#.#\n..O..#O..O\n.......O..\n#....###..\n#OO..#....";
+
+    #[test]
+    fn sample() {
+        let platform = Platform::new(SAMPLE);
+        assert_eq!(part1(&platform), 136);
+        assert_eq!(part2(&platform), 64);
+    }
+
+    #[test]
+    fn render_program_shows_every_tilt() {
+        let platform = Platform::new(".O.\nO.#\n..O");
+        assert_eq!(platform.render_program(&TiltProgram::new("NE")), ".O.\nO.#\n..O\n\nN\nOO.\n..#\n..O\n\nE\n.OO\n..#\n..O");
+    }
+}
@@ -1,4 +1,9 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
 use std::fs;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
 use itertools::Itertools;
 
 fn hash(data: &str) -> usize {
@@ -12,71 +17,262 @@ fn hash(data: &str) -> usize {
     current
 }
 
-struct Entry {
-    label: String,
-    focal_length: usize,
+#[derive(Debug)]
+enum ParseError {
+    MissingOperation(String),
+    InvalidLabel(String),
+    InvalidFocalLength(String),
+    InvalidBox(String),
 }
 
-struct CustomHashMapBox {
-    entries: Vec<Entry>,
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingOperation(step) => write!(f, "step \"{}\" is neither an insertion nor a removal", step),
+            ParseError::InvalidLabel(step) => write!(f, "step \"{}\" has an invalid label", step),
+            ParseError::InvalidFocalLength(step) => write!(f, "step \"{}\" has an invalid focal length", step),
+            ParseError::InvalidBox(line) => write!(f, "invalid box description \"{}\"", line),
+        }
+    }
+}
+
+fn valid_label(label: &str) -> bool {
+    !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn parse_focal_length(focal: &str) -> Option<usize> {
+    focal.parse().ok().filter(|focal_length| (1..=9).contains(focal_length))
+}
+
+enum Instruction {
+    Insert { label: String, focal_length: usize },
+    Remove { label: String },
+}
+
+impl Instruction {
+    fn new(step: &str) -> Result<Self, ParseError> {
+        let instruction = if let Some((label, focal)) = step.split_once("=") {
+            let focal_length = parse_focal_length(focal)
+                .ok_or_else(|| ParseError::InvalidFocalLength(step.to_string()))?;
+            Instruction::Insert { label: label.to_string(), focal_length }
+        } else if let Some(label) = step.strip_suffix("-") {
+            Instruction::Remove { label: label.to_string() }
+        } else {
+            return Err(ParseError::MissingOperation(step.to_string()));
+        };
+        if valid_label(instruction.label()) {
+            Ok(instruction)
+        } else {
+            Err(ParseError::InvalidLabel(step.to_string()))
+        }
+    }
+
+    fn label(&self) -> &str {
+        match self {
+            Instruction::Insert { label, .. } => label,
+            Instruction::Remove { label } => label,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Insert { label, focal_length } => write!(f, "{}={}", label, focal_length),
+            Instruction::Remove { label } => write!(f, "{}-", label),
+        }
+    }
+}
+
+fn parse_instructions(data: &str) -> Result<Vec<Instruction>, ParseError> {
+    data.trim()
+        .split(",")
+        .map(Instruction::new)
+        .collect()
 }
 
-impl CustomHashMapBox {
+struct Entry<K, V> {
+    key: K,
+    value: V,
+}
+
+struct CustomHashMapBox<K, V> {
+    entries: Vec<Entry<K, V>>,
+}
+
+impl<K: Eq, V> CustomHashMapBox<K, V> {
     fn new() -> Self {
         Self {
             entries: vec![],
         }
     }
 
-    fn remove(&mut self, label: &str) -> Option<Entry> {
-        let position = self.entries
+    fn position(&self, key: &K) -> Option<usize> {
+        self.entries
             .iter()
-            .find_position(|x| x.label.eq(label));
-        match position {
-            None => None,
-            Some((pos, _)) => Some(self.entries.remove(pos))
-        }
+            .find_position(|x| x.key.eq(key))
+            .map(|(pos, _)| pos)
     }
 
-    fn insert(&mut self, entry: Entry) {
-        let position = self.entries
-            .iter()
-            .find_position(|x| x.label.eq(entry.label.as_str()));
-        match position {
-            None => self.entries.push(entry),
-            Some((pos, _)) => self.entries[pos] = entry
+    fn get(&self, key: &K) -> Option<&V> {
+        self.position(key).map(|pos| &self.entries[pos].value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        self.position(key).map(|pos| self.entries.remove(pos).value)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.position(&key) {
+            None => {
+                self.entries.push(Entry { key, value });
+                None
+            }
+            Some(pos) => Some(std::mem::replace(&mut self.entries[pos].value, value))
         }
     }
 
+    fn iter(&self) -> impl Iterator<Item=(&K, &V)> {
+        self.entries.iter().map(|entry| (&entry.key, &entry.value))
+    }
+}
+
+impl<K: Eq + fmt::Display, V: fmt::Display> CustomHashMapBox<K, V> {
+    fn render(&self) -> String {
+        self.iter()
+            .map(|(key, value)| format!("[{} {}]", key, value))
+            .join(" ")
+    }
+}
+
+impl<K: Eq> CustomHashMapBox<K, usize> {
     fn focusing_power(&self) -> usize {
         self.entries.iter().enumerate()
-            .map(|(lens_slot, lens)| (lens_slot + 1) * lens.focal_length)
+            .map(|(lens_slot, lens)| (lens_slot + 1) * lens.value)
             .sum()
     }
 }
 
-struct CustomHashMap {
-    boxes: Vec<CustomHashMapBox>,
+// fixed number of boxes, each keeping its entries in insertion order
+struct CustomHashMap<K, V, H: Fn(&K) -> usize> {
+    boxes: Vec<CustomHashMapBox<K, V>>,
+    hasher: H,
 }
 
-impl CustomHashMap {
-    fn new() -> Self {
+impl<K: Eq, V, H: Fn(&K) -> usize> CustomHashMap<K, V, H> {
+    fn new(bucket_count: usize, hasher: H) -> Self {
+        assert!(bucket_count > 0, "a CustomHashMap needs at least one bucket");
         Self {
-            boxes: (0..256).map(|_| CustomHashMapBox::new()).collect()
+            boxes: (0..bucket_count).map(|_| CustomHashMapBox::new()).collect(),
+            hasher,
         }
     }
 
-    fn process_operation(&mut self, op: &str) {
-        if op.contains("=") {
-            let (label, focal) = op.split("=").collect_tuple().unwrap();
-            let entry = Entry { label: label.to_string(), focal_length: focal.parse().unwrap() };
-            let h = hash(label);
-            self.boxes[h].insert(entry);
-        } else {
-            let label = op.replace("-", "");
-            let h = hash(label.as_str());
-            self.boxes[h].remove(label.as_str());
+    fn bucket(&self, key: &K) -> usize {
+        (self.hasher)(key) % self.boxes.len()
+    }
+
+    fn get(&self, key: &K) -> Option<&V> {
+        self.boxes[self.bucket(key)].get(key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let h = self.bucket(&key);
+        self.boxes[h].insert(key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let h = self.bucket(key);
+        self.boxes[h].remove(key)
+    }
+
+    fn len(&self) -> usize {
+        self.boxes.iter().map(|b| b.entries.len()).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // boxes in order, entries of a box in insertion order
+    fn iter(&self) -> impl Iterator<Item=(&K, &V)> {
+        self.boxes.iter().flat_map(|b| b.iter())
+    }
+}
+
+impl<K: Eq + fmt::Display, V: fmt::Display, H: Fn(&K) -> usize> CustomHashMap<K, V, H> {
+    // non-empty boxes in the format used by the puzzle statement, e.g. "Box 0: [rn 1] [cm 2]"
+    fn dump(&self) -> String {
+        self.boxes.iter().enumerate()
+            .filter(|(_, lens_box)| !lens_box.entries.is_empty())
+            .map(|(box_id, lens_box)| format!("Box {}: {}", box_id, lens_box.render()))
+            .join("\n")
+    }
+}
+
+type LensHashMap = CustomHashMap<String, usize, fn(&String) -> usize>;
+
+impl LensHashMap {
+    fn lenses() -> Self {
+        Self::new(256, |label| hash(label))
+    }
+
+    fn process_instruction(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Insert { label, focal_length } => {
+                self.insert(label.clone(), *focal_length);
+            }
+            Instruction::Remove { label } => {
+                self.remove(label);
+            }
+        }
+    }
+
+    fn run_traced(&mut self, instructions: &[Instruction], on_step: &mut impl FnMut(&Instruction, &Self)) {
+        for instruction in instructions {
+            self.process_instruction(instruction);
+            on_step(instruction, self);
+        }
+    }
+
+    fn run(&mut self, instructions: &[Instruction]) {
+        self.run_traced(instructions, &mut |_, _| {});
+    }
+
+    fn trace(&mut self, instructions: &[Instruction]) -> String {
+        let mut steps = vec![];
+        self.run_traced(instructions, &mut |instruction, hashmap| {
+            steps.push(format!("After \"{}\":\n{}", instruction, hashmap.dump()));
+        });
+        steps.join("\n\n")
+    }
+
+    fn restore(data: &str) -> Result<Self, ParseError> {
+        let mut hashmap = Self::lenses();
+        for line in data.lines().filter(|line| !line.trim().is_empty()) {
+            let invalid = || ParseError::InvalidBox(line.to_string());
+            let (box_id, contents) = line.trim()
+                .strip_prefix("Box ")
+                .and_then(|rest| rest.split_once(":"))
+                .ok_or_else(invalid)?;
+            let box_id: usize = box_id.parse().map_err(|_| invalid())?;
+            if box_id >= hashmap.boxes.len() || !hashmap.boxes[box_id].entries.is_empty() {
+                return Err(invalid());
+            }
+            for lens in contents.split_whitespace().collect_vec().chunks(2) {
+                let (label, focal) = match lens {
+                    [label, focal] => (label.strip_prefix("[").ok_or_else(invalid)?, focal.strip_suffix("]").ok_or_else(invalid)?),
+                    _ => return Err(invalid())
+                };
+                let focal_length = parse_focal_length(focal).ok_or_else(invalid)?;
+                let label = label.to_string();
+                if !valid_label(&label) || hashmap.bucket(&label) != box_id || hashmap.get(&label).is_some() {
+                    return Err(invalid());
+                }
+                hashmap.insert(label, focal_length);
+            }
         }
+        Ok(hashmap)
     }
 
     fn focusing_power(&self) -> usize {
@@ -86,16 +282,47 @@ impl CustomHashMap {
     }
 }
 
-fn part2(data: &str) -> usize {
-    let mut hashmap = CustomHashMap::new();
-    for op in data.split(",") {
-        hashmap.process_operation(op);
+// runs the same instructions against the custom container and the standard one,
+// reading every value back so the optimizer cannot skip the work being measured
+fn benchmark(instructions: &[Instruction], rounds: usize) -> (Duration, Duration) {
+    let start = Instant::now();
+    for _ in 0..rounds {
+        let mut hashmap = LensHashMap::lenses();
+        hashmap.run(black_box(instructions));
+        black_box(hashmap.iter().map(|(_, focal_length)| focal_length).sum::<usize>());
+        black_box(hashmap);
+    }
+    let custom = start.elapsed();
+    let start = Instant::now();
+    for _ in 0..rounds {
+        let mut hashmap = HashMap::new();
+        for instruction in black_box(instructions) {
+            match instruction {
+                Instruction::Insert { label, focal_length } => {
+                    hashmap.insert(label.clone(), *focal_length);
+                }
+                Instruction::Remove { label } => {
+                    hashmap.remove(label);
+                }
+            }
+        }
+        black_box(hashmap.values().sum::<usize>());
+        black_box(hashmap);
     }
+    (custom, start.elapsed())
+}
+
+const BENCHMARK_ROUNDS: usize = 1000;
+
+fn part2(data: &str) -> usize {
+    let mut hashmap = LensHashMap::lenses();
+    hashmap.run(&parse_instructions(data).unwrap());
     hashmap.focusing_power()
 }
 
 fn part1(data: &str) -> usize {
-    data.split(",")
+    data.trim()
+        .split(",")
         .map(|step| hash(step))
         .sum()
 }
@@ -104,4 +331,46 @@ pub(crate) fn solve() {
     let contents = fs::read_to_string("15.txt").unwrap();
     println!("{}", part1(&contents));
     println!("{}", part2(&contents));
+    if env::args().any(|arg| arg == "--benchmark") {
+        let (custom, standard) = benchmark(&parse_instructions(&contents).unwrap(), BENCHMARK_ROUNDS);
+        println!("{} rounds: CustomHashMap {:?}, HashMap {:?}", BENCHMARK_ROUNDS, custom, standard);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "rn=1,cm-,qp=3,cm=2,qp-,pc=4,ot=9,ab=5,pc-,pc=6,ot=7";
+
+    #[test]
+    fn sample() {
+        assert_eq!(part1(SAMPLE), 1320);
+        assert_eq!(part2(SAMPLE), 145);
+    }
+
+    #[test]
+    fn trace_follows_the_puzzle_statement() {
+        let trace = LensHashMap::lenses().trace(&parse_instructions(SAMPLE).unwrap());
+        assert!(trace.starts_with("After \"rn=1\":\nBox 0: [rn 1]\n\nAfter \"cm-\":\nBox 0: [rn 1]\n\n"));
+        assert!(trace.ends_with("After \"ot=7\":\nBox 0: [rn 1] [cm 2]\nBox 3: [ot 7] [ab 5] [pc 6]"));
+    }
+
+    #[test]
+    fn dump_and_restore_round_trip() {
+        let mut hashmap = LensHashMap::lenses();
+        hashmap.run(&parse_instructions(SAMPLE).unwrap());
+        let restored = LensHashMap::restore(&hashmap.dump()).unwrap();
+        assert_eq!(restored.dump(), hashmap.dump());
+        assert_eq!(restored.iter().collect_vec(), hashmap.iter().collect_vec());
+        assert_eq!(restored.focusing_power(), 145);
+        // lenses in a box their label does not hash to
+        assert!(LensHashMap::restore("Box 1: [rn 1]").is_err());
+    }
+
+    #[test]
+    fn benchmark_runs_both_containers() {
+        let (custom, standard) = benchmark(&parse_instructions(SAMPLE).unwrap(), 3);
+        assert!(custom > Duration::ZERO && standard > Duration::ZERO);
+    }
 }
\ No newline at end of file
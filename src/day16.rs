@@ -1,10 +1,13 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::hash::Hash;
+use std::rc::Rc;
 use itertools::{Itertools};
+use petgraph::algo::tarjan_scc;
+use petgraph::graph::{DiGraph, NodeIndex};
 use crate::day16::Tile::{AngleLeft, AngleRight, Empty, HorizontalSplit, VerticalSplit};
 
-#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
 enum Direction {
     Up,
     Down,
@@ -21,6 +24,24 @@ impl Direction {
             Direction::Right => (1, 0)
         }
     }
+
+    fn arrow(&self) -> char {
+        match self {
+            Direction::Up => '^',
+            Direction::Down => 'v',
+            Direction::Left => '<',
+            Direction::Right => '>'
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Direction::Up => "up",
+            Direction::Down => "down",
+            Direction::Left => "left",
+            Direction::Right => "right"
+        }
+    }
 }
 
 enum Tile {
@@ -43,6 +64,16 @@ impl Tile {
         }
     }
 
+    fn symbol(&self) -> char {
+        match self {
+            Empty => '.',
+            HorizontalSplit => '-',
+            VerticalSplit => '|',
+            AngleRight => '/',
+            AngleLeft => '\\',
+        }
+    }
+
     fn handle_beam(&self, direction: Direction) -> Vec<Direction> {
         match self {
             Empty => vec![direction],
@@ -70,27 +101,190 @@ impl Tile {
     }
 }
 
-#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
 struct Beam {
     pos: (i64, i64),
     direction: Direction,
 }
 
+trait Optic {
+    fn symbol(&self) -> char;
+
+    // empty tiles let beams through untouched, renderings draw the beams over them
+    fn is_empty(&self) -> bool {
+        false
+    }
+
+    // beam has just entered the tile at `beam.pos` travelling in `beam.direction`
+    fn handle_beam(&self, beam: Beam) -> Vec<Beam>;
+}
+
+impl Optic for Tile {
+    fn symbol(&self) -> char {
+        Tile::symbol(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        matches!(self, Empty)
+    }
+
+    fn handle_beam(&self, beam: Beam) -> Vec<Beam> {
+        Tile::handle_beam(self, beam.direction).into_iter()
+            .map(|direction| Beam { pos: beam.pos, direction })
+            .collect()
+    }
+}
+
+struct Absorber;
+
+impl Optic for Absorber {
+    fn symbol(&self) -> char {
+        '#'
+    }
+
+    fn handle_beam(&self, _: Beam) -> Vec<Beam> {
+        vec![]
+    }
+}
+
+struct FourWaySplit;
+
+impl Optic for FourWaySplit {
+    fn symbol(&self) -> char {
+        '+'
+    }
+
+    fn handle_beam(&self, beam: Beam) -> Vec<Beam> {
+        [Direction::Up, Direction::Down, Direction::Left, Direction::Right].into_iter()
+            .map(|direction| Beam { pos: beam.pos, direction })
+            .collect()
+    }
+}
+
+// mirror with a single reflective face, beams reaching it from the other side pass through
+struct OneWayMirror {
+    symbol: char,
+    mirror: Tile,
+    reflected: [Direction; 2],
+}
+
+impl Optic for OneWayMirror {
+    fn symbol(&self) -> char {
+        self.symbol
+    }
+
+    fn handle_beam(&self, beam: Beam) -> Vec<Beam> {
+        if self.reflected.contains(&beam.direction) {
+            Optic::handle_beam(&self.mirror, beam)
+        } else {
+            vec![beam]
+        }
+    }
+}
+
+struct Portal {
+    symbol: char,
+    target: (i64, i64),
+}
+
+impl Optic for Portal {
+    fn symbol(&self) -> char {
+        self.symbol
+    }
+
+    fn handle_beam(&self, beam: Beam) -> Vec<Beam> {
+        vec![Beam { pos: self.target, direction: beam.direction }]
+    }
+}
+
+struct TileRegistry {
+    optics: HashMap<char, Rc<dyn Optic>>,
+    portals: HashSet<char>,
+}
+
+impl TileRegistry {
+    fn standard() -> Self {
+        let mut registry = Self { optics: HashMap::new(), portals: HashSet::new() };
+        for c in ['.', '-', '|', '/', '\\'] {
+            registry.register(c, Rc::new(Tile::new(&c)));
+        }
+        registry
+    }
+
+    // one-way mirrors are named after the pipe (as in day 10) pointing where reflected beams go
+    fn extended() -> Self {
+        let mut registry = Self::standard();
+        registry.register('#', Rc::new(Absorber));
+        registry.register('+', Rc::new(FourWaySplit));
+        registry.register('J', Rc::new(OneWayMirror { symbol: 'J', mirror: AngleRight, reflected: [Direction::Right, Direction::Down] }));
+        registry.register('F', Rc::new(OneWayMirror { symbol: 'F', mirror: AngleRight, reflected: [Direction::Left, Direction::Up] }));
+        registry.register('L', Rc::new(OneWayMirror { symbol: 'L', mirror: AngleLeft, reflected: [Direction::Left, Direction::Down] }));
+        registry.register('7', Rc::new(OneWayMirror { symbol: '7', mirror: AngleLeft, reflected: [Direction::Right, Direction::Up] }));
+        for c in 'a'..='z' {
+            registry.register_portal(c);
+        }
+        registry
+    }
+
+    fn register(&mut self, symbol: char, optic: Rc<dyn Optic>) {
+        self.optics.insert(symbol, optic);
+    }
+
+    // a portal symbol has to appear exactly twice in the grid, entering one leaves through the other
+    fn register_portal(&mut self, symbol: char) {
+        self.portals.insert(symbol);
+    }
+}
+
+// tiles energized by a single beam until it leaves the grid, loops, or gets split
+struct Trace {
+    tiles: Vec<(i64, i64)>,
+    split: Option<Beam>,
+}
+
+// every beam arriving at a splitting tile from a given direction energizes the same tiles,
+// so those are computed once per strongly connected group of splits and shared
+struct SplitGraph {
+    width: i64,
+    energized: HashMap<Beam, Vec<u64>>,
+}
+
 struct Grid {
-    tiles: Vec<Vec<Tile>>,
+    tiles: Vec<Vec<Rc<dyn Optic>>>,
 }
 
 impl Grid {
     fn new(data: &str) -> Self {
+        Self::with_registry(data, &TileRegistry::standard())
+    }
+
+    fn with_registry(data: &str, registry: &TileRegistry) -> Self {
+        let portals = data.lines().enumerate()
+            .flat_map(|(y, line)| line.chars().enumerate()
+                .filter(|(_, c)| registry.portals.contains(c))
+                .map(move |(x, c)| (c, (x as i64, y as i64))))
+            .into_group_map();
         Self {
-            tiles: data.lines()
-                .map(|line| line.chars()
-                    .map(|c| Tile::new(&c))
+            tiles: data.lines().enumerate()
+                .map(|(y, line)| line.chars().enumerate()
+                    .map(|(x, c)| match portals.get(&c) {
+                        Some(ends) if ends.len() == 2 => {
+                            let target = if ends[0] == (x as i64, y as i64) { ends[1] } else { ends[0] };
+                            Rc::new(Portal { symbol: c, target }) as Rc<dyn Optic>
+                        }
+                        Some(_) => panic!(),
+                        None => registry.optics.get(&c).unwrap().clone()
+                    })
                     .collect())
                 .collect()
         }
     }
 
+    fn handle_beam(&self, beam: Beam) -> Vec<Beam> {
+        let (x, y) = beam.pos;
+        self.tiles[y as usize][x as usize].handle_beam(beam)
+    }
+
     fn height(&self) -> i64 {
         self.tiles.len() as i64
     }
@@ -103,7 +297,9 @@ impl Grid {
         x >= 0 && y >= 0 && x < self.width() && y < self.height()
     }
 
-    fn progress_beams(&self, beams: &HashSet<Beam>) -> HashSet<Beam> {
+    // beams entering the next tiles, and the beams leaving those tiles
+    fn progress_beams(&self, beams: &HashSet<Beam>) -> (Vec<Beam>, HashSet<Beam>) {
+        let mut entered_beams = vec![];
         let mut progressed_beams = HashSet::new();
         for beam in beams {
             let (dx, dy) = beam.direction.move_vector();
@@ -111,29 +307,153 @@ impl Grid {
             let nx = x + dx;
             let ny = y + dy;
             if self.is_in_grid(nx, ny) {
-                let (dest_x, dest_y) = (nx as usize, ny as usize);
-                let destination_tile = self.tiles.get(dest_y).unwrap().get(dest_x).unwrap();
-                let resulting_beams = destination_tile.handle_beam(beam.direction);
-                for d in resulting_beams {
-                    progressed_beams.insert(Beam { pos: (nx, ny), direction: d });
-                }
+                let entered = Beam { pos: (nx, ny), direction: beam.direction };
+                entered_beams.push(entered);
+                progressed_beams.extend(self.handle_beam(entered));
             }
         }
-        progressed_beams
+        (entered_beams, progressed_beams)
     }
 
     fn energize(&self, starting_beam: Beam) -> HashSet<Beam> {
         let mut all_beams = HashSet::new();
+        let mut propagated = HashSet::new();
         let mut beams = HashSet::from([starting_beam]);
-        loop {
-            beams = self.progress_beams(&beams);
-            if beams.difference(&all_beams).count() == 0 {
+        while !beams.is_empty() {
+            let (entered_beams, progressed_beams) = self.progress_beams(&beams);
+            all_beams.extend(entered_beams);
+            beams = progressed_beams.difference(&propagated).copied().collect();
+            propagated.extend(&beams);
+            all_beams.extend(&beams);
+        }
+        all_beams
+    }
+
+    fn render_energized(&self, beams: &HashSet<Beam>) -> String {
+        let energized: HashSet<(i64, i64)> = beams.iter().map(|beam| beam.pos).collect();
+        (0..self.height())
+            .map(|y| (0..self.width())
+                .map(|x| if energized.contains(&(x, y)) { '#' } else { '.' })
+                .collect::<String>())
+            .join("\n")
+    }
+
+    // like in the puzzle statement, optical elements are kept and empty tiles show beam directions or their count
+    fn render_beams(&self, beams: &HashSet<Beam>) -> String {
+        let directions = beams.iter().into_group_map_by(|beam| beam.pos);
+        self.tiles.iter().enumerate()
+            .map(|(y, row)| row.iter().enumerate()
+                .map(|(x, tile)| match (tile, directions.get(&(x as i64, y as i64))) {
+                    (tile, Some(passing)) if tile.is_empty() && passing.len() == 1 => passing[0].direction.arrow(),
+                    (tile, Some(passing)) if tile.is_empty() => char::from_digit(passing.len() as u32, 10).unwrap(),
+                    _ => tile.symbol()
+                })
+                .collect::<String>())
+            .join("\n")
+    }
+
+    fn export_json(&self, beams: &HashSet<Beam>) -> String {
+        let energized = beams.iter()
+            .map(|beam| beam.pos)
+            .unique()
+            .sorted()
+            .map(|(x, y)| format!("[{},{}]", x, y))
+            .join(",");
+        let beam_list = beams.iter()
+            .sorted()
+            .map(|beam| format!("{{\"x\":{},\"y\":{},\"direction\":\"{}\"}}", beam.pos.0, beam.pos.1, beam.direction.name()))
+            .join(",");
+        format!("{{\"width\":{},\"height\":{},\"energized\":[{}],\"beams\":[{}]}}", self.width(), self.height(), energized, beam_list)
+    }
+
+    fn trace(&self, starting_beam: Beam) -> Trace {
+        let mut tiles = vec![];
+        let mut seen = HashSet::new();
+        let mut beam = starting_beam;
+        while seen.insert(beam) {
+            let (dx, dy) = beam.direction.move_vector();
+            let (nx, ny) = (beam.pos.0 + dx, beam.pos.1 + dy);
+            if !self.is_in_grid(nx, ny) {
                 break;
-            } else {
-                all_beams.extend(&beams);
+            }
+            tiles.push((nx, ny));
+            let resulting_beams = self.handle_beam(Beam { pos: (nx, ny), direction: beam.direction });
+            if resulting_beams.len() != 1 {
+                let split = Some(Beam { pos: (nx, ny), direction: beam.direction }).filter(|_| !resulting_beams.is_empty());
+                return Trace { tiles, split };
+            }
+            beam = resulting_beams[0];
+            if beam.pos != (nx, ny) {
+                tiles.push(beam.pos);
             }
         }
-        all_beams
+        Trace { tiles, split: None }
+    }
+
+    fn split_graph(&self) -> SplitGraph {
+        let mut graph = DiGraph::new();
+        let mut nodes: HashMap<Beam, NodeIndex> = HashMap::new();
+        for (y, row) in self.tiles.iter().enumerate() {
+            for (x, tile) in row.iter().enumerate() {
+                for direction in [Direction::Up, Direction::Down, Direction::Left, Direction::Right] {
+                    let beam = Beam { pos: (x as i64, y as i64), direction };
+                    if tile.handle_beam(beam).len() > 1 {
+                        nodes.insert(beam, graph.add_node(beam));
+                    }
+                }
+            }
+        }
+        let mut own_tiles = HashMap::new();
+        for (&split, &node) in &nodes {
+            let mut tiles = vec![split.pos];
+            for beam in self.handle_beam(split) {
+                tiles.push(beam.pos);
+                let trace = self.trace(beam);
+                tiles.extend(trace.tiles);
+                if let Some(next) = trace.split {
+                    graph.add_edge(node, *nodes.get(&next).unwrap(), ());
+                }
+            }
+            own_tiles.insert(node, tiles);
+        }
+        let mut energized_by_node: HashMap<NodeIndex, Vec<u64>> = HashMap::new();
+        let words = ((self.width() * self.height()) as usize).div_ceil(64);
+        for component in tarjan_scc(&graph) { // successors come before predecessors
+            let mut bits = vec![0u64; words];
+            for node in &component {
+                for &(x, y) in own_tiles.get(node).unwrap() {
+                    let index = (y * self.width() + x) as usize;
+                    bits[index / 64] |= 1 << (index % 64);
+                }
+                for successor in graph.neighbors(*node) {
+                    if let Some(successor_bits) = energized_by_node.get(&successor) {
+                        bits.iter_mut().zip(successor_bits).for_each(|(a, b)| *a |= b);
+                    }
+                }
+            }
+            for node in component {
+                energized_by_node.insert(node, bits.clone());
+            }
+        }
+        SplitGraph {
+            width: self.width(),
+            energized: nodes.iter()
+                .map(|(beam, node)| (*beam, energized_by_node.remove(node).unwrap()))
+                .collect(),
+        }
+    }
+
+    fn count_energized_with(&self, graph: &SplitGraph, starting_beam: Beam) -> usize {
+        let trace = self.trace(starting_beam);
+        let mut bits = match trace.split {
+            None => vec![0u64; ((self.width() * self.height()) as usize).div_ceil(64)],
+            Some(split) => graph.energized.get(&split).unwrap().clone()
+        };
+        for (x, y) in trace.tiles {
+            let index = (y * graph.width + x) as usize;
+            bits[index / 64] |= 1 << (index % 64);
+        }
+        bits.iter().map(|word| word.count_ones() as usize).sum()
     }
 }
 
@@ -150,14 +470,15 @@ fn part1(grid: &Grid) -> usize {
 }
 
 fn part2(grid: &Grid) -> usize {
+    let graph = grid.split_graph();
     let mut possible_solutions = vec![];
     for x in 0..grid.width() {
-        possible_solutions.push(count_energized(grid, Beam { pos: (x, -1), direction: Direction::Down }));
-        possible_solutions.push(count_energized(grid, Beam { pos: (x, grid.height()), direction: Direction::Up }));
+        possible_solutions.push(grid.count_energized_with(&graph, Beam { pos: (x, -1), direction: Direction::Down }));
+        possible_solutions.push(grid.count_energized_with(&graph, Beam { pos: (x, grid.height()), direction: Direction::Up }));
     }
     for y in 0..grid.height() {
-        possible_solutions.push(count_energized(grid, Beam { pos: (-1, y), direction: Direction::Right }));
-        possible_solutions.push(count_energized(grid, Beam { pos: (grid.width(), y), direction: Direction::Left }));
+        possible_solutions.push(grid.count_energized_with(&graph, Beam { pos: (-1, y), direction: Direction::Right }));
+        possible_solutions.push(grid.count_energized_with(&graph, Beam { pos: (grid.width(), y), direction: Direction::Left }));
     }
     *possible_solutions.iter().max().unwrap()
 }
@@ -167,4 +488,60 @@ pub(crate) fn solve() {
     let grid = Grid::new(&contents);
     println!("{}", part1(&grid));
     println!("{}", part2(&grid));
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r".|...\....
+|.-.\.....
+.....|-...
+........|.
+..........
+.........\
+..../.\\..
+.-.-/..|..
+.|....-|.\
+..//.|....";
+
+    #[test]
+    fn sample() {
+        let grid = Grid::new(SAMPLE);
+        assert_eq!(part1(&grid), 46);
+        assert_eq!(part2(&grid), 51);
+    }
+
+    #[test]
+    fn renders_and_exports_the_sample() {
+        let grid = Grid::new(SAMPLE);
+        let beams = grid.energize(Beam { pos: (-1, 0), direction: Direction::Right });
+        assert_eq!(grid.render_energized(&beams), "\
+######....
+.#...#....
+.#...#####
+.#...##...
+.#...##...
+.#...##...
+.#..####..
+########..
+.#######..
+.#...#.#..");
+        assert_eq!(grid.render_beams(&beams), r">|<<<\....
+|v-.\^....
+.v...|->>>
+.v...v^.|.
+.v...v^...
+.v...v^..\
+.v../2\\..
+<->-/vv|..
+.|<<<2-|.\
+.v//.|.v..");
+        let json = grid.export_json(&beams);
+        assert!(json.starts_with("{\"width\":10,\"height\":10,\"energized\":[[0,0],[0,7],[1,0],"));
+        assert!(json.ends_with("]}"));
+        let (energized, beam_list) = json.split_once("\"beams\":").unwrap();
+        assert_eq!(energized.matches("],[").count() + 1, 46);
+        assert_eq!(beam_list.matches("\"direction\"").count(), beams.len());
+    }
+}
@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::fs;
 use std::ops::Add;
 
@@ -20,6 +21,44 @@ impl Direction {
         }
     }
 
+    fn index(&self) -> usize {
+        match self {
+            Direction::Up => 0,
+            Direction::Down => 1,
+            Direction::Left => 2,
+            Direction::Right => 3,
+        }
+    }
+
+    fn from_index(index: usize) -> Self {
+        match index {
+            0 => Direction::Up,
+            1 => Direction::Down,
+            2 => Direction::Left,
+            3 => Direction::Right,
+            _ => panic!()
+        }
+    }
+
+    // y grows with every input line, so `Up` moves down the rendered map
+    fn arrow(&self) -> char {
+        match self {
+            Direction::Up => 'v',
+            Direction::Down => '^',
+            Direction::Left => '<',
+            Direction::Right => '>',
+        }
+    }
+
+    fn opposite(&self) -> Direction {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+
     fn perpendicular(&self) -> (Direction, Direction) {
         match self {
             Direction::Up | Direction::Down => (Direction::Left, Direction::Right),
@@ -53,26 +92,6 @@ impl Add<&Direction> for Position {
     }
 }
 
-fn simple_directions(state: &State) -> Vec<State> {
-    let (turn1, turn2) = state.last_direction.perpendicular();
-    if state.steps_done < 3 {
-        vec![state.step(&state.last_direction), state.step(&turn1), state.step(&turn2)]
-    } else {
-        vec![state.step(&turn1), state.step(&turn2)]
-    }
-}
-
-fn ultra_directions(state: &State) -> Vec<State> {
-    let (turn1, turn2) = state.last_direction.perpendicular();
-    if state.steps_done < 4 {
-        vec![state.step(&state.last_direction)]
-    } else if state.steps_done < 10 {
-        vec![state.step(&state.last_direction), state.step(&turn1), state.step(&turn2)]
-    } else {
-        vec![state.step(&turn1), state.step(&turn2)]
-    }
-}
-
 #[derive(PartialEq, Eq, Hash, Clone, Copy)]
 struct State {
     position: Position,
@@ -94,72 +113,193 @@ impl State {
     }
 }
 
+struct MovementRules {
+    min_run: u64,
+    max_run: u64,
+    reversals: bool,
+    start: Position,
+    target: Position,
+}
+
+impl MovementRules {
+    fn crucible(heatmap: &Heatmap) -> Self {
+        Self {
+            min_run: 1,
+            max_run: 3,
+            reversals: false,
+            start: Position { x: 0, y: 0 },
+            target: Position { x: heatmap.width() - 1, y: heatmap.height() - 1 },
+        }
+    }
+
+    fn ultra_crucible(heatmap: &Heatmap) -> Self {
+        Self {
+            min_run: 4,
+            max_run: 10,
+            ..Self::crucible(heatmap)
+        }
+    }
+
+    // the crucible leaves the start in any direction, and has to complete the minimal run before turning
+    fn next_states(&self, state: &State) -> Vec<State> {
+        let mut next_states = vec![];
+        if state.steps_done < self.max_run {
+            next_states.push(state.step(&state.last_direction));
+        }
+        if state.steps_done > 0 && state.steps_done >= self.min_run {
+            let (turn1, turn2) = state.last_direction.perpendicular();
+            next_states.push(state.step(&turn1));
+            next_states.push(state.step(&turn2));
+            if self.reversals {
+                next_states.push(state.step(&state.last_direction.opposite()));
+            }
+        }
+        next_states
+    }
+
+    fn can_stop(&self, state: &State) -> bool {
+        state.position == self.target && state.steps_done >= self.min_run
+    }
+}
+
+struct SearchResult {
+    heat_loss: u64,
+    states_expanded: usize,
+    path: Vec<(Position, Direction)>,
+}
+
 struct Heatmap {
-    heatmap: HashMap<Position, u64>,
+    heatmap: Vec<Vec<u64>>,
 }
 
 impl Heatmap {
     fn new(data: &str) -> Self {
         Self {
             heatmap: data.lines()
-                .enumerate()
-                .map(|(y, line)| line.chars().enumerate()
-                    .map(move |(x, c)| (Position { x: x as i64, y: y as i64 }, c.to_string().as_str().parse().unwrap())))
-                .flatten()
+                .map(|line| line.chars()
+                    .map(|c| c.to_digit(10).unwrap() as u64)
+                    .collect())
                 .collect()
         }
     }
 
     fn height(&self) -> i64 {
-        self.heatmap.keys().map(|c| c.y).max().unwrap()
+        self.heatmap.len() as i64
     }
+
     fn width(&self) -> i64 {
-        self.heatmap.keys().map(|c| c.x).max().unwrap()
-    }
-
-    fn find_heat_losses(&self, directions: impl Fn(&State) -> Vec<State>) -> HashMap<State, u64> {
-        let mut visited = HashMap::new();
-        let s1 = State { position: Position { x: 0, y: 0 }, last_direction: Direction::Right, steps_done: 0 };
-        let s2 = State { position: Position { x: 0, y: 0 }, last_direction: Direction::Up, steps_done: 0 };
-        visited.insert(s1, 0);
-        visited.insert(s2, 0);
-        let mut to_check = vec![s1, s2];
-        while !to_check.is_empty() {
-            let mut new_to_check = vec![];
-            for state in to_check {
-                let current_loss = *visited.get(&state).unwrap();
-                for next_key in directions(&state) {
-                    if self.heatmap.contains_key(&next_key.position) {
-                        let next_loss = current_loss + self.heatmap.get(&next_key.position).unwrap();
-                        if !visited.contains_key(&next_key) || *visited.get(&next_key).unwrap() > next_loss {
-                            visited.insert(next_key, next_loss);
-                            new_to_check.push(next_key);
-                        }
+        self.heatmap[0].len() as i64
+    }
+
+    fn heat_loss(&self, position: &Position) -> Option<u64> {
+        if position.x < 0 || position.y < 0 || position.x >= self.width() || position.y >= self.height() {
+            None
+        } else {
+            Some(self.heatmap[position.y as usize][position.x as usize])
+        }
+    }
+
+    // states are laid out as (y, x, direction, steps done) in a single flat array
+    fn state_index(&self, state: &State, max_run: u64) -> usize {
+        let cell = (state.position.y * self.width() + state.position.x) as usize;
+        (cell * 4 + state.last_direction.index()) * (max_run as usize + 1) + state.steps_done as usize
+    }
+
+    fn state_at(&self, index: usize, max_run: u64) -> State {
+        let steps_done = (index % (max_run as usize + 1)) as u64;
+        let rest = index / (max_run as usize + 1);
+        let cell = (rest / 4) as i64;
+        State {
+            position: Position { x: cell % self.width(), y: cell / self.width() },
+            last_direction: Direction::from_index(rest % 4),
+            steps_done,
+        }
+    }
+
+    // A* with manhattan distance scaled by the cheapest block, which never overestimates the remaining loss
+    fn find_min_heat_loss(&self, rules: &MovementRules) -> Option<SearchResult> {
+        if self.heat_loss(&rules.start).is_none() || self.heat_loss(&rules.target).is_none() {
+            return None;
+        }
+        let max_run = rules.max_run;
+        let target = rules.target;
+        let cheapest = self.heatmap.iter().flatten().min().copied().unwrap_or(0);
+        let estimate = |position: &Position| ((target.x - position.x).abs() + (target.y - position.y).abs()) as u64 * cheapest;
+        let mut losses = vec![u64::MAX; (self.width() * self.height()) as usize * 4 * (max_run as usize + 1)];
+        let mut predecessors = vec![usize::MAX; losses.len()];
+        let mut queue = BinaryHeap::new();
+        for direction in [Direction::Up, Direction::Down, Direction::Left, Direction::Right] {
+            let start = State { position: rules.start, last_direction: direction, steps_done: 0 };
+            let index = self.state_index(&start, max_run);
+            losses[index] = 0;
+            queue.push(Reverse((estimate(&start.position), 0, index)));
+        }
+        let mut states_expanded = 0;
+        while let Some(Reverse((_, current_loss, index))) = queue.pop() {
+            if current_loss > losses[index] {
+                continue;
+            }
+            states_expanded += 1;
+            let state = self.state_at(index, max_run);
+            if rules.can_stop(&state) {
+                let path = self.reconstruct_path(&predecessors, index, max_run);
+                return Some(SearchResult { heat_loss: current_loss, states_expanded, path });
+            }
+            for next_state in rules.next_states(&state) {
+                if next_state.steps_done > max_run {
+                    continue;
+                }
+                if let Some(heat_loss) = self.heat_loss(&next_state.position) {
+                    let next_loss = current_loss + heat_loss;
+                    let next_index = self.state_index(&next_state, max_run);
+                    if next_loss < losses[next_index] {
+                        losses[next_index] = next_loss;
+                        predecessors[next_index] = index;
+                        queue.push(Reverse((next_loss + estimate(&next_state.position), next_loss, next_index)));
                     }
                 }
             }
-            to_check = new_to_check;
         }
-        visited
+        None
+    }
+
+    // blocks entered along the way, together with the direction the crucible moved in
+    fn reconstruct_path(&self, predecessors: &[usize], end: usize, max_run: u64) -> Vec<(Position, Direction)> {
+        let mut path = vec![];
+        let mut index = end;
+        while predecessors[index] != usize::MAX {
+            let state = self.state_at(index, max_run);
+            path.push((state.position, state.last_direction));
+            index = predecessors[index];
+        }
+        path.reverse();
+        path
+    }
+
+    fn render_path(&self, path: &[(Position, Direction)]) -> String {
+        let mut rendered: Vec<Vec<char>> = self.heatmap.iter()
+            .map(|row| row.iter().map(|&loss| char::from_digit(loss as u32, 10).unwrap()).collect())
+            .collect();
+        for (position, direction) in path {
+            rendered[position.y as usize][position.x as usize] = direction.arrow();
+        }
+        rendered.iter()
+            .map(|row| row.iter().collect::<String>())
+            .collect::<Vec<String>>()
+            .join("\n")
     }
 }
 
 fn part2(heatmap: &Heatmap) -> u64 {
-    *heatmap.find_heat_losses(ultra_directions)
-        .iter()
-        .filter(|(k, _)| k.position.x == heatmap.width() && k.position.y == heatmap.height() && k.steps_done >= 4)
-        .map(|(_, v)| v)
-        .min()
+    heatmap.find_min_heat_loss(&MovementRules::ultra_crucible(heatmap))
         .unwrap()
+        .heat_loss
 }
 
 fn part1(heatmap: &Heatmap) -> u64 {
-    *heatmap.find_heat_losses(simple_directions)
-        .iter()
-        .filter(|(k, _)| k.position.x == heatmap.width() && k.position.y == heatmap.height())
-        .map(|(_, v)| v)
-        .min()
+    heatmap.find_min_heat_loss(&MovementRules::crucible(heatmap))
         .unwrap()
+        .heat_loss
 }
 
 pub(crate) fn solve() {
@@ -167,4 +307,70 @@ pub(crate) fn solve() {
     let heatmap = Heatmap::new(&contents);
     println!("{}", part1(&heatmap));
     println!("{}", part2(&heatmap));
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "2413432311323
+3215453535623
+3255245654254
+3446585845452
+4546657867536
+1438598798454
+4457876987766
+3637877979653
+4654967986887
+4564679986453
+1224686865563
+2546548887735
+4322674655533";
+
+    // the path must start next to the start, move one block at a time and lose exactly the reported heat
+    fn check_path(heatmap: &Heatmap, rules: &MovementRules) -> SearchResult {
+        let result = heatmap.find_min_heat_loss(rules).unwrap();
+        let mut position = rules.start;
+        for (next, direction) in &result.path {
+            assert!(*next == position + direction);
+            position = *next;
+        }
+        assert!(position == rules.target);
+        let heat_loss: u64 = result.path.iter()
+            .map(|(position, _)| heatmap.heat_loss(position).unwrap())
+            .sum();
+        assert_eq!(heat_loss, result.heat_loss);
+        assert!(result.states_expanded >= result.path.len());
+        result
+    }
+
+    #[test]
+    fn sample() {
+        let heatmap = Heatmap::new(SAMPLE);
+        assert_eq!(part1(&heatmap), 102);
+        assert_eq!(part2(&heatmap), 94);
+    }
+
+    #[test]
+    fn paths_lose_the_reported_heat() {
+        let heatmap = Heatmap::new(SAMPLE);
+        let crucible = check_path(&heatmap, &MovementRules::crucible(&heatmap));
+        assert_eq!(crucible.heat_loss, part1(&heatmap));
+        let ultra_crucible = check_path(&heatmap, &MovementRules::ultra_crucible(&heatmap));
+        assert_eq!(ultra_crucible.heat_loss, part2(&heatmap));
+        assert_eq!(heatmap.render_path(&ultra_crucible.path), "\
+2>>>>>>>>1323
+32154535v5623
+32552456v4254
+34465858v5452
+45466578v>>>>
+143859879845v
+445787698776v
+363787797965v
+465496798688v
+456467998645v
+122468686556v
+254654888773v
+432267465553v");
+    }
+}
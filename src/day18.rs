@@ -1,6 +1,9 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
 use std::ops::Add;
 use itertools::Itertools;
+use crate::polygon::{path_self_intersection, Polygon};
 
 #[derive(PartialEq, Eq, Hash, Clone, Copy)]
 enum Direction {
@@ -89,7 +92,256 @@ impl DigStep {
         Self {
             direction: Direction::new_from_color(&(stripped.as_str().as_bytes()[stripped.len() - 1] as char)),
             steps: i64::from_str_radix(&stripped.as_str()[..stripped.len() - 1], 16).unwrap(),
-            color: "".to_string(),
+            color: self.color.clone(),
+        }
+    }
+
+    fn rgb(&self) -> (u8, u8, u8) {
+        let stripped = self.color.replace("(", "")
+            .replace(")", "")
+            .replace("#", "");
+        let channel = |i: usize| u8::from_str_radix(&stripped[2 * i..2 * i + 2], 16).unwrap();
+        (channel(0), channel(1), channel(2))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LagoonTile {
+    Outside,
+    Trench((u8, u8, u8)),
+    Interior,
+}
+
+impl LagoonTile {
+    fn symbol(&self) -> char {
+        match self {
+            LagoonTile::Outside => '.',
+            LagoonTile::Trench(_) | LagoonTile::Interior => '#',
+        }
+    }
+
+    fn rgb(&self) -> (u8, u8, u8) {
+        match self {
+            LagoonTile::Outside => (255, 255, 255),
+            LagoonTile::Trench(color) => *color,
+            LagoonTile::Interior => (128, 128, 128),
+        }
+    }
+}
+
+// bands of coordinates given as (first, length), a single tile each unless compressed
+fn bands(coordinates: &[i64], compress: bool) -> Vec<(i64, i64)> {
+    let sorted: Vec<i64> = coordinates.iter().copied().sorted().dedup().collect();
+    let (first, last) = (sorted[0], sorted[sorted.len() - 1]);
+    if !compress {
+        return (first..=last).map(|c| (c, 1)).collect();
+    }
+    let mut bands = vec![];
+    for (a, b) in sorted.iter().tuple_windows() {
+        bands.push((*a, 1));
+        if b - a > 1 {
+            bands.push((a + 1, b - a - 1));
+        }
+    }
+    bands.push((last, 1));
+    bands
+}
+
+// dug plan laid out on a grid, rows go from the top (largest y) down
+struct Lagoon {
+    columns: Vec<(i64, i64)>,
+    rows: Vec<(i64, i64)>,
+    tiles: Vec<Vec<LagoonTile>>,
+}
+
+impl Lagoon {
+    fn new(steps: &[DigStep], compress: bool) -> Self {
+        let mut segments = vec![];
+        let mut current = Position { x: 0, y: 0 };
+        for step in steps {
+            let next = current + step;
+            segments.push((current, next, step.rgb()));
+            current = next;
+        }
+        // both ends of every segment, so that a plan which does not return to the start still has its last corner
+        let corners = segments.iter().flat_map(|(start, end, _)| [*start, *end]).collect_vec();
+        let columns = bands(&corners.iter().map(|corner| corner.x).collect_vec(), compress);
+        let mut rows = bands(&corners.iter().map(|corner| corner.y).collect_vec(), compress);
+        rows.reverse();
+        let column_index: HashMap<i64, usize> = columns.iter().enumerate().map(|(i, (start, _))| (*start, i)).collect();
+        let row_index: HashMap<i64, usize> = rows.iter().enumerate().map(|(i, (start, _))| (*start, i)).collect();
+        let mut tiles = vec![vec![LagoonTile::Outside; columns.len()]; rows.len()];
+        for (start, end, color) in &segments {
+            let (c1, c2) = (column_index[&start.x], column_index[&end.x]);
+            let (r1, r2) = (row_index[&start.y], row_index[&end.y]);
+            for row in tiles.iter_mut().take(r1.max(r2) + 1).skip(r1.min(r2)) {
+                for tile in row.iter_mut().take(c1.max(c2) + 1).skip(c1.min(c2)) {
+                    *tile = LagoonTile::Trench(*color);
+                }
+            }
+        }
+        for (r, (y, _)) in rows.iter().enumerate() {
+            // a tile is inside when a ray to the left crosses the trench an odd number of times,
+            // vertical segments count for the lower end only so corners are not counted twice
+            let crossings: Vec<i64> = segments.iter()
+                .filter(|(start, end, _)| start.x == end.x && start.y.min(end.y) <= *y && *y < start.y.max(end.y))
+                .map(|(start, _, _)| start.x)
+                .collect();
+            for (c, (x, _)) in columns.iter().enumerate() {
+                if tiles[r][c] == LagoonTile::Outside && crossings.iter().filter(|&&cx| cx < *x).count() % 2 == 1 {
+                    tiles[r][c] = LagoonTile::Interior;
+                }
+            }
+        }
+        Self {
+            columns,
+            rows,
+            tiles,
+        }
+    }
+
+    fn dug_area(&self) -> i64 {
+        let mut area = 0;
+        for (r, (_, height)) in self.rows.iter().enumerate() {
+            for (c, (_, width)) in self.columns.iter().enumerate() {
+                if self.tiles[r][c] != LagoonTile::Outside {
+                    area += width * height;
+                }
+            }
+        }
+        area
+    }
+
+    fn render_text(&self) -> String {
+        self.tiles.iter()
+            .map(|row| row.iter().map(LagoonTile::symbol).collect::<String>())
+            .join("\n")
+    }
+
+    fn pixels(&self) -> Vec<u8> {
+        self.tiles.iter()
+            .flat_map(|row| row.iter().flat_map(|tile| {
+                let (r, g, b) = tile.rgb();
+                [r, g, b]
+            }))
+            .collect()
+    }
+
+    fn to_ppm(&self) -> Vec<u8> {
+        let mut image = format!("P6\n{} {}\n255\n", self.columns.len(), self.rows.len()).into_bytes();
+        image.extend(self.pixels());
+        image
+    }
+
+    // uncompressed (stored deflate blocks) RGB image, readable by any PNG viewer
+    fn to_png(&self) -> Vec<u8> {
+        let (width, height) = (self.columns.len() as u32, self.rows.len() as u32);
+        let pixels = self.pixels();
+        let mut raw = vec![];
+        for row in pixels.chunks(width as usize * 3) {
+            raw.push(0);
+            raw.extend(row);
+        }
+        let mut zlib = vec![0x78, 0x01];
+        let blocks = raw.chunks(65535).collect_vec();
+        for (i, block) in blocks.iter().enumerate() {
+            let length = block.len() as u16;
+            zlib.push(if i + 1 == blocks.len() { 1 } else { 0 });
+            zlib.extend(length.to_le_bytes());
+            zlib.extend((!length).to_le_bytes());
+            zlib.extend(*block);
+        }
+        if blocks.is_empty() {
+            zlib.extend([1, 0, 0, 0xff, 0xff]);
+        }
+        zlib.extend(adler32(&raw).to_be_bytes());
+        let mut header = vec![];
+        header.extend(width.to_be_bytes());
+        header.extend(height.to_be_bytes());
+        header.extend([8, 2, 0, 0, 0]);
+        let mut image = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+        png_chunk(&mut image, b"IHDR", &header);
+        png_chunk(&mut image, b"IDAT", &zlib);
+        png_chunk(&mut image, b"IEND", &[]);
+        image
+    }
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn png_chunk(image: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    image.extend((data.len() as u32).to_be_bytes());
+    let mut checked = kind.to_vec();
+    checked.extend(data);
+    image.extend(&checked);
+    image.extend(crc32(&checked).to_be_bytes());
+}
+
+struct BoundingBox {
+    min: Position,
+    max: Position,
+}
+
+struct PlanValidation {
+    end: Position,
+    bounding_box: BoundingBox,
+    // indices of the two steps and the first tile they share
+    intersection: Option<(usize, usize, Position)>,
+}
+
+impl PlanValidation {
+    fn closed(&self) -> bool {
+        self.end == Position { x: 0, y: 0 }
+    }
+}
+
+impl fmt::Display for PlanValidation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.closed() {
+            writeln!(f, "returns to the start")?;
+        } else {
+            writeln!(f, "ends at ({}, {})", self.end.x, self.end.y)?;
+        }
+        match self.intersection {
+            Some((first_step, second_step, at)) =>
+                writeln!(f, "step {} runs into step {} at ({}, {})", second_step + 1, first_step + 1, at.x, at.y)?,
+            None => writeln!(f, "never runs into itself")?
+        }
+        write!(f, "bounding box ({}, {}) to ({}, {})", self.bounding_box.min.x, self.bounding_box.min.y, self.bounding_box.max.x, self.bounding_box.max.y)
+    }
+}
+
+#[derive(Debug)]
+enum PlanError {
+    NotClosed { x: i64, y: i64 },
+    SelfIntersection { first_step: usize, second_step: usize, x: i64, y: i64 },
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanError::NotClosed { x, y } =>
+                write!(f, "dig plan ends at ({}, {}) instead of returning to the start", x, y),
+            PlanError::SelfIntersection { first_step, second_step, x, y } =>
+                write!(f, "step {} runs into step {} at ({}, {})", second_step + 1, first_step + 1, x, y),
         }
     }
 }
@@ -105,48 +357,168 @@ impl DigPlan {
         }
     }
 
-    fn area(&self, steps: &Vec<DigStep>) -> i64 {
+    fn vertices(&self, steps: &[DigStep]) -> Vec<(i64, i64)> {
         let mut coords = vec![];
         let mut current = Position { x: 0, y: 0 };
-        coords.push(current);
+        coords.push((current.x, current.y));
         for step in steps {
             current = current + step;
-            coords.push(current);
+            coords.push((current.x, current.y));
         }
-        let internal_area = shoelace_area(&coords);
-        let edge_area = (steps.iter().map(|s| s.steps).sum::<i64>()) / 2 + 1;
-        internal_area + edge_area
+        coords
     }
 
-    fn simple_area(&self) -> i64 {
-        self.area(&self.plan)
+    fn validate(&self, steps: &[DigStep]) -> PlanValidation {
+        let coords = self.vertices(steps);
+        let (&(end_x, end_y), _) = coords.split_last().unwrap();
+        let bounding_box = BoundingBox {
+            min: Position { x: coords.iter().map(|c| c.0).min().unwrap(), y: coords.iter().map(|c| c.1).min().unwrap() },
+            max: Position { x: coords.iter().map(|c| c.0).max().unwrap(), y: coords.iter().map(|c| c.1).max().unwrap() },
+        };
+        let end = Position { x: end_x, y: end_y };
+        let edges = coords.windows(2).map(|pair| (pair[0], pair[1])).collect_vec();
+        // an open path has no edge back to the start, step i is edge i either way
+        let intersection = if end == (Position { x: 0, y: 0 }) {
+            Polygon::new(coords).self_intersection()
+        } else {
+            path_self_intersection(&coords)
+        };
+        let intersection = intersection.map(|i| {
+            let (a, b) = (edges[i.first_edge], edges[i.second_edge]);
+            // trench segments are axis aligned, so they share a rectangle of tiles
+            let x = a.0.0.min(a.1.0).max(b.0.0.min(b.1.0));
+            let y = a.0.1.min(a.1.1).max(b.0.1.min(b.1.1));
+            (i.first_edge, i.second_edge, Position { x, y })
+        });
+        PlanValidation {
+            end,
+            bounding_box,
+            intersection,
+        }
     }
-    fn color_area(&self) -> i64 {
-        self.area(&self.plan.iter().map(|s| s.color_step()).collect())
+
+    fn check(&self, steps: &[DigStep]) -> Result<(), PlanError> {
+        let validation = self.validate(steps);
+        if !validation.closed() {
+            return Err(PlanError::NotClosed { x: validation.end.x, y: validation.end.y });
+        }
+        if let Some((first_step, second_step, at)) = validation.intersection {
+            return Err(PlanError::SelfIntersection { first_step, second_step, x: at.x, y: at.y });
+        }
+        Ok(())
+    }
+
+    fn area(&self, steps: &[DigStep]) -> Result<i64, PlanError> {
+        self.check(steps)?;
+        Ok(Polygon::new(self.vertices(steps)).enclosed_points())
     }
-}
 
-fn shoelace_area(coords: &Vec<Position>) -> i64 {
-    let mut s = 0;
-    for pair in coords.windows(2) {
-        let (c1, c2) = pair.iter().collect_tuple().unwrap();
-        let tmp = (c1.x * c2.y) - (c1.y * c2.x);
-        s += tmp
+    fn lagoon(&self, steps: &[DigStep], compress: bool) -> Result<Lagoon, PlanError> {
+        self.check(steps)?;
+        Ok(Lagoon::new(steps, compress))
+    }
+
+    fn simple_lagoon(&self) -> Result<Lagoon, PlanError> {
+        self.lagoon(&self.plan, false)
+    }
+
+    fn color_steps(&self) -> Vec<DigStep> {
+        self.plan.iter().map(|s| s.color_step()).collect_vec()
+    }
+
+    fn color_lagoon(&self) -> Result<Lagoon, PlanError> {
+        self.lagoon(&self.color_steps(), true)
+    }
+
+    fn simple_area(&self) -> Result<i64, PlanError> {
+        self.area(&self.plan)
+    }
+    fn color_area(&self) -> Result<i64, PlanError> {
+        self.area(&self.color_steps())
     }
-    s.abs() / 2
 }
 
-fn part1(plan: &DigPlan) -> i64 {
+fn part1(plan: &DigPlan) -> Result<i64, PlanError> {
     plan.simple_area()
 }
 
-fn part2(plan: &DigPlan) -> i64 {
+fn part2(plan: &DigPlan) -> Result<i64, PlanError> {
     plan.color_area()
 }
 
+// prints the area, or what is wrong with the plan together with the full validation report
+fn print_area(plan: &DigPlan, steps: &[DigStep], area: Result<i64, PlanError>) {
+    match area {
+        Ok(area) => println!("{}", area),
+        Err(error) => println!("{}\n{}", error, plan.validate(steps))
+    }
+}
+
 pub(crate) fn solve() {
     let contents = fs::read_to_string("18.txt").unwrap();
     let dig_plan = DigPlan::new(&contents);
-    println!("{}", part1(&dig_plan));
-    println!("{}", part2(&dig_plan));
-}
\ No newline at end of file
+    print_area(&dig_plan, &dig_plan.plan, part1(&dig_plan));
+    print_area(&dig_plan, &dig_plan.color_steps(), part2(&dig_plan));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "R 6 (#70c710)\nD 5 (#0dc571)\nL 2 (#5713f0)\nD 2 (#d2c081)\nR 2 (#59c680)\nD 2 (#411b91)\nL 5 (#8ceee2)\nU 2 (#caa173)\nL 1 (#1b58a2)\nU 2 (#caa171)\nR 2 (#7807d2)\nU 3 (#a77fa3)\nL 2 (#015232)\nU 2 (#7a21e3)";
+
+    #[test]
+    fn sample() {
+        let plan = DigPlan::new(SAMPLE);
+        assert_eq!(part1(&plan).unwrap(), 62);
+        assert_eq!(part2(&plan).unwrap(), 952408144115);
+    }
+
+    #[test]
+    fn folding_back_is_rejected() {
+        let plan = DigPlan::new("R 4 (#000000)\nL 4 (#000000)");
+        assert!(matches!(plan.simple_area(), Err(PlanError::SelfIntersection { first_step: 0, second_step: 1, .. })));
+    }
+
+    #[test]
+    fn open_path_reports_crossing_and_bounding_box() {
+        let plan = DigPlan::new("R 4 (#000000)\nD 2 (#000000)\nL 2 (#000000)\nU 4 (#000000)");
+        assert!(matches!(plan.simple_area(), Err(PlanError::NotClosed { x: 2, y: 2 })));
+        assert_eq!(plan.validate(&plan.plan).to_string(), "ends at (2, 2)\nstep 4 runs into step 1 at (2, 0)\nbounding box (0, -2) to (4, 2)");
+    }
+
+    #[test]
+    fn lagoon_matches_the_area() {
+        let plan = DigPlan::new(SAMPLE);
+        let lagoon = plan.simple_lagoon().unwrap();
+        assert_eq!(lagoon.dug_area(), plan.simple_area().unwrap());
+        assert_eq!(lagoon.render_text(), "\
+#######
+#######
+#######
+..#####
+..#####
+#######
+#####..
+#######
+.######
+.######");
+        assert_eq!(plan.color_lagoon().unwrap().dug_area(), plan.color_area().unwrap());
+    }
+
+    #[test]
+    fn exports_have_valid_headers_and_sizes() {
+        let lagoon = DigPlan::new(SAMPLE).simple_lagoon().unwrap();
+        let ppm = lagoon.to_ppm();
+        let header = b"P6\n7 10\n255\n";
+        assert!(ppm.starts_with(header));
+        assert_eq!(ppm.len(), header.len() + 7 * 10 * 3);
+        let png = lagoon.to_png();
+        assert!(png.starts_with(&[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]));
+        assert_eq!(&png[8..16], &[0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(&png[16..24], &[0, 0, 0, 7, 0, 0, 0, 10]);
+        // signature, header chunk, one stored block holding a filter byte per row, end chunk
+        assert_eq!(png.len(), 8 + 25 + 12 + 2 + 5 + 10 * (1 + 7 * 3) + 4 + 12);
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+    }
+}
@@ -1,22 +1,43 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt;
 use std::fs;
 use itertools::Itertools;
+use petgraph::algo::tarjan_scc;
+use petgraph::graph::DiGraph;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 enum Comparison {
     Greater,
     Lesser,
     GreaterOrEq,
     LesserOrEq,
+    Equal,
+    NotEqual,
 }
 
 impl Comparison {
+    // two character operators first so that `<=` is not read as `<`
+    const ALL: [Comparison; 6] = [Comparison::GreaterOrEq, Comparison::LesserOrEq, Comparison::Equal, Comparison::NotEqual, Comparison::Greater, Comparison::Lesser];
+
     fn evaluate(&self, v1: &i64, v2: &i64) -> bool {
         match self {
             Comparison::Greater => v1 > v2,
             Comparison::Lesser => v1 < v2,
             Comparison::GreaterOrEq => v1 >= v2,
-            Comparison::LesserOrEq => v1 <= v2
+            Comparison::LesserOrEq => v1 <= v2,
+            Comparison::Equal => v1 == v2,
+            Comparison::NotEqual => v1 != v2
+        }
+    }
+
+    fn symbol(&self) -> &str {
+        match self {
+            Comparison::Greater => ">",
+            Comparison::Lesser => "<",
+            Comparison::GreaterOrEq => ">=",
+            Comparison::LesserOrEq => "<=",
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!="
         }
     }
 
@@ -25,110 +46,498 @@ impl Comparison {
             Comparison::Greater => Comparison::LesserOrEq,
             Comparison::Lesser => Comparison::GreaterOrEq,
             Comparison::GreaterOrEq => Comparison::Lesser,
-            Comparison::LesserOrEq => Comparison::Greater
+            Comparison::LesserOrEq => Comparison::Greater,
+            Comparison::Equal => Comparison::NotEqual,
+            Comparison::NotEqual => Comparison::Equal
         }
     }
 }
 
-#[derive(Clone)]
-struct Condition {
+#[derive(Clone, PartialEq, Eq)]
+enum Operand {
+    Variable(String),
+    Constant(i64),
+}
+
+impl Operand {
+    fn new(data: &str) -> Self {
+        match data.parse() {
+            Ok(constant) => Operand::Constant(constant),
+            Err(_) => Operand::Variable(data.to_string())
+        }
+    }
+
+    fn value(&self, query: &Query) -> i64 {
+        match self {
+            Operand::Variable(name) => query.value(name),
+            Operand::Constant(constant) => *constant
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Variable(name) => write!(f, "{}", name),
+            Operand::Constant(constant) => write!(f, "{}", constant)
+        }
+    }
+}
+
+// a single comparison of a variable with a constant or with another variable
+#[derive(Clone, PartialEq, Eq)]
+struct Predicate {
     variable_name: String,
     comparison: Comparison,
-    constant: i64,
+    operand: Operand,
 }
 
-impl Condition {
+impl Predicate {
     fn new(comp: &str) -> Self {
-        let comparison;
-        let mut variable_name = "";
-        let mut constant = "0";
-        if comp.contains("<") {
-            comparison = Comparison::Lesser;
-            (variable_name, constant) = comp.split("<").collect_tuple().unwrap();
-        } else {
-            comparison = Comparison::Greater;
-            (variable_name, constant) = comp.split(">").collect_tuple().unwrap();
-        }
+        let (comparison, (variable_name, operand)) = Comparison::ALL.iter()
+            .find_map(|comparison| comp.split_once(comparison.symbol()).map(|parts| (comparison.clone(), parts)))
+            .unwrap_or_else(|| panic!("no comparison in {}", comp));
         Self {
             variable_name: variable_name.to_string(),
             comparison,
-            constant: constant.parse().unwrap(),
+            operand: Operand::new(operand),
         }
     }
 
-    fn true_condition() -> Self {
-        Self {
-            variable_name: "TRUE".to_string(),
-            comparison: Comparison::Greater,
-            constant: -1,
-        }
+    // the variable on the left and, when comparing two variables, the one on the right
+    fn variables(&self) -> impl Iterator<Item = &String> {
+        let other = match &self.operand {
+            Operand::Variable(name) => Some(name),
+            Operand::Constant(_) => None
+        };
+        std::iter::once(&self.variable_name).chain(other)
     }
 
     fn negate(&self) -> Self {
         Self {
             variable_name: self.variable_name.to_string(),
             comparison: self.comparison.opposite(),
-            constant: self.constant,
+            operand: self.operand.clone(),
         }
     }
 
     fn evaluate(&self, query: &Query) -> bool {
-        self.simple_evaluate(query.variables.get(&self.variable_name).unwrap_or(&0))
+        self.comparison.evaluate(&query.value(&self.variable_name), &self.operand.value(query))
     }
 
-    fn simple_evaluate(&self, value: &i64) -> bool {
-        self.comparison.evaluate(value, &self.constant)
+    fn explain(&self, query: &Query) -> String {
+        let mut values = format!("{}={}", self.variable_name, query.value(&self.variable_name));
+        if let Operand::Variable(other) = &self.operand {
+            values += &format!(", {}={}", other, query.value(other));
+        }
+        let verdict = if self.evaluate(query) { "satisfies" } else { "fails" };
+        format!("{} {} {}", values, verdict, self)
+    }
+
+    fn allowed_values(&self, constant: i64, min: i64, max: i64) -> IntervalSet {
+        let range = match self.comparison {
+            Comparison::Greater => constant.checked_add(1).map(|from| (from, i64::MAX)),
+            Comparison::GreaterOrEq => Some((constant, i64::MAX)),
+            Comparison::Lesser => constant.checked_sub(1).map(|to| (i64::MIN, to)),
+            Comparison::LesserOrEq => Some((i64::MIN, constant)),
+            Comparison::Equal | Comparison::NotEqual => Some((constant, constant)),
+        };
+        let allowed = range.map_or(IntervalSet::empty(), |(from, to)| IntervalSet::new(from.max(min), to.min(max)));
+        match self.comparison {
+            Comparison::NotEqual => allowed.complement(min, max),
+            _ => allowed
+        }
     }
 }
 
-struct ConditionsChain {
-    conditions: Vec<Condition>,
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{}", self.variable_name, self.comparison.symbol(), self.operand)
+    }
 }
 
-impl ConditionsChain {
-    fn combinations(&self) -> i64 {
-        self.group_conditions_by_variable()
-            .iter()
-            .map(|c| c.solutions_count())
-            .product::<i64>()
+// conjunction of predicates, written as `x>10&m<a`
+#[derive(Clone, PartialEq, Eq)]
+struct Condition {
+    predicates: Vec<Predicate>,
+}
+
+impl Condition {
+    fn new(comp: &str) -> Self {
+        Self {
+            predicates: comp.split("&").map(Predicate::new).collect()
+        }
+    }
+
+    fn evaluate(&self, query: &Query) -> bool {
+        self.predicates.iter().all(|predicate| predicate.evaluate(query))
+    }
+
+    // why the condition holds (every predicate) or fails (the first predicate which does not hold)
+    fn explain(&self, query: &Query) -> String {
+        match self.predicates.iter().find(|predicate| !predicate.evaluate(query)) {
+            Some(failed) => failed.explain(query),
+            None => self.predicates.iter().map(|predicate| predicate.explain(query)).join(", ")
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.predicates.iter().join("&"))
+    }
+}
+
+// sorted, disjoint and non-adjacent inclusive ranges
+#[derive(Clone, PartialEq, Eq)]
+struct IntervalSet {
+    intervals: Vec<(i64, i64)>,
+}
+
+impl IntervalSet {
+    fn new(from: i64, to: i64) -> Self {
+        if from > to {
+            Self::empty()
+        } else {
+            Self { intervals: vec![(from, to)] }
+        }
+    }
+
+    fn empty() -> Self {
+        Self { intervals: vec![] }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+
+    fn size(&self) -> u128 {
+        self.intervals.iter()
+            .map(|(from, to)| (*to as i128 - *from as i128 + 1) as u128)
+            .sum()
+    }
+
+    fn intersect(&self, other: &IntervalSet) -> Self {
+        let mut intervals = vec![];
+        let (mut i, mut j) = (0, 0);
+        while i < self.intervals.len() && j < other.intervals.len() {
+            let (a_from, a_to) = self.intervals[i];
+            let (b_from, b_to) = other.intervals[j];
+            if a_from.max(b_from) <= a_to.min(b_to) {
+                intervals.push((a_from.max(b_from), a_to.min(b_to)));
+            }
+            if a_to < b_to {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        Self { intervals }
+    }
+
+    fn union(&self, other: &IntervalSet) -> Self {
+        let mut intervals: Vec<(i64, i64)> = vec![];
+        for (from, to) in self.intervals.iter().chain(other.intervals.iter()).copied().sorted() {
+            match intervals.last_mut() {
+                Some(last) if from <= last.1.saturating_add(1) => last.1 = last.1.max(to),
+                _ => intervals.push((from, to))
+            }
+        }
+        Self { intervals }
+    }
+
+    fn complement(&self, min: i64, max: i64) -> Self {
+        let mut intervals = vec![];
+        let mut next = min;
+        for &(from, to) in &self.intervals {
+            if from > next {
+                intervals.push((next, from - 1));
+            }
+            if to == i64::MAX {
+                return Self { intervals };
+            }
+            next = next.max(to + 1);
+        }
+        if next <= max {
+            intervals.push((next, max));
+        }
+        Self { intervals }.intersect(&IntervalSet::new(min, max))
+    }
+}
+
+impl fmt::Display for IntervalSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.intervals.iter().map(|(from, to)| format!("{}..={}", from, to)).join(" | "))
+    }
+}
+
+// exact non-negative integer of arbitrary size, stored as little-endian base 2^32 digits
+#[derive(Clone, PartialEq, Eq)]
+struct Count {
+    digits: Vec<u32>,
+}
+
+impl Count {
+    fn new(value: u128) -> Self {
+        let mut digits = vec![];
+        let mut value = value;
+        while value > 0 {
+            digits.push(value as u32);
+            value >>= 32;
+        }
+        Self { digits }
+    }
+
+    fn add(&self, other: &Count) -> Self {
+        let mut digits = vec![];
+        let mut carry = 0u64;
+        for i in 0..self.digits.len().max(other.digits.len()) {
+            let sum = carry + *self.digits.get(i).unwrap_or(&0) as u64 + *other.digits.get(i).unwrap_or(&0) as u64;
+            digits.push(sum as u32);
+            carry = sum >> 32;
+        }
+        if carry > 0 {
+            digits.push(carry as u32);
+        }
+        Self { digits }
+    }
+
+    fn mul(&self, other: &Count) -> Self {
+        let mut digits = vec![0u32; self.digits.len() + other.digits.len()];
+        for (i, &a) in self.digits.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &b) in other.digits.iter().enumerate() {
+                let current = digits[i + j] as u64 + a as u64 * b as u64 + carry;
+                digits[i + j] = current as u32;
+                carry = current >> 32;
+            }
+            digits[i + other.digits.len()] = carry as u32;
+        }
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        Self { digits }
+    }
+
+    fn divide(&self, divisor: u32) -> Self {
+        let mut digits = self.digits.clone();
+        let mut remainder = 0u64;
+        for digit in digits.iter_mut().rev() {
+            let current = (remainder << 32) | *digit as u64;
+            *digit = (current / divisor as u64) as u32;
+            remainder = current % divisor as u64;
+        }
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        Self { digits }
+    }
+
+    fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    fn binomial(n: u128, k: usize) -> Self {
+        if (k as u128) > n {
+            return Count::new(0);
+        }
+        // every prefix of the product is itself a binomial coefficient, so each division is exact
+        (0..k).fold(Count::new(1), |acc, i| acc.mul(&Count::new(n - i as u128)).divide(i as u32 + 1))
+    }
+}
+
+impl fmt::Display for Count {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut chunks = vec![];
+        let mut digits = self.digits.clone();
+        while !digits.is_empty() {
+            let mut remainder = 0u64;
+            for digit in digits.iter_mut().rev() {
+                let current = (remainder << 32) | *digit as u64;
+                *digit = (current / 1_000_000_000) as u32;
+                remainder = current % 1_000_000_000;
+            }
+            chunks.push(remainder);
+            while digits.last() == Some(&0) {
+                digits.pop();
+            }
+        }
+        match chunks.split_last() {
+            None => write!(f, "0"),
+            Some((first, rest)) => {
+                write!(f, "{}", first)?;
+                rest.iter().rev().try_for_each(|chunk| write!(f, "{:09}", chunk))
+            }
+        }
+    }
+}
+
+// bounds of every variable a part can have
+struct Domain {
+    bounds: BTreeMap<String, (i64, i64)>,
+}
+
+impl Domain {
+    fn uniform<'a>(variables: impl IntoIterator<Item = &'a String>, min: i64, max: i64) -> Self {
+        Self {
+            bounds: variables.into_iter().map(|v| (v.clone(), (min, max))).collect()
+        }
+    }
+
+    fn bounds(&self, variable: &str) -> (i64, i64) {
+        *self.bounds.get(variable)
+            .unwrap_or_else(|| panic!("variable {} is missing from the domain", variable))
+    }
+}
+
+// set of parts given as allowed values of each variable, further restricted by comparisons between variables
+#[derive(Clone)]
+struct Region {
+    variables: BTreeMap<String, IntervalSet>,
+    relations: Vec<Predicate>,
+}
+
+impl Region {
+    fn new(domain: &Domain) -> Self {
+        Self {
+            variables: domain.bounds.iter()
+                .map(|(name, (min, max))| (name.clone(), IntervalSet::new(*min, *max)))
+                .collect(),
+            relations: vec![],
+        }
     }
 
-    fn group_conditions_by_variable(&self) -> Vec<ConditionsChain> {
-        let mut x = vec![Condition::true_condition()];
-        let mut m = vec![Condition::true_condition()];
-        let mut a = vec![Condition::true_condition()];
-        let mut s = vec![Condition::true_condition()];
-        for c in &self.conditions {
-            match c.variable_name.as_str() {
-                "x" => { x.push(c.clone()) }
-                "m" => { m.push(c.clone()) }
-                "a" => { a.push(c.clone()) }
-                "s" => { s.push(c.clone()) }
-                "TRUE" => {}
-                _ => panic!()
+    fn is_empty(&self) -> bool {
+        self.variables.values().any(|values| values.is_empty())
+            || (!self.relations.is_empty() && self.combinations().is_zero())
+    }
+
+    fn restrict(&self, predicate: &Predicate, domain: &Domain) -> Self {
+        let mut region = self.clone();
+        let (min, max) = domain.bounds(&predicate.variable_name);
+        match &predicate.operand {
+            Operand::Constant(constant) => {
+                let values = region.variables.get_mut(&predicate.variable_name).unwrap();
+                *values = values.intersect(&predicate.allowed_values(*constant, min, max));
+            }
+            Operand::Variable(other) if other == &predicate.variable_name => {
+                if !predicate.comparison.evaluate(&0, &0) {
+                    region.variables.insert(other.clone(), IntervalSet::empty());
+                }
+            }
+            Operand::Variable(other) => {
+                domain.bounds(other);
+                region.relations.push(predicate.clone());
             }
         }
-        vec![
-            ConditionsChain { conditions: x },
-            ConditionsChain { conditions: m },
-            ConditionsChain { conditions: a },
-            ConditionsChain { conditions: s },
-        ]
+        region
     }
 
-    fn is_accepted(&self, value: &i64) -> bool {
-        self.conditions.iter().all(|c| c.simple_evaluate(value))
+    fn matching(&self, condition: &Condition, domain: &Domain) -> Region {
+        condition.predicates.iter()
+            .fold(self.clone(), |region, predicate| region.restrict(predicate, domain))
     }
 
-    fn solutions_count(&self) -> i64 {
-        (1..=4000)
-            .filter(|v| self.is_accepted(v))
-            .count() as i64
+    // parts failing a conjunction, split into disjoint regions by the first predicate they fail
+    fn not_matching(&self, condition: &Condition, domain: &Domain) -> Vec<Region> {
+        let mut regions = vec![];
+        let mut passed = self.clone();
+        for predicate in &condition.predicates {
+            regions.push(passed.restrict(&predicate.negate(), domain));
+            passed = passed.restrict(predicate, domain);
+        }
+        regions
+    }
+
+    fn combinations(&self) -> Count {
+        let related = self.related_groups();
+        let free = self.variables.iter()
+            .filter(|(name, _)| !related.iter().any(|group| group.contains(name)))
+            .fold(Count::new(1), |acc, (_, values)| acc.mul(&Count::new(values.size())));
+        related.iter()
+            .fold(free, |acc, group| acc.mul(&self.group_combinations(group)))
+    }
+
+    // variables linked by relations, which have to be counted together
+    fn related_groups(&self) -> Vec<Vec<&String>> {
+        let mut groups: Vec<Vec<&String>> = vec![];
+        for relation in &self.relations {
+            let Operand::Variable(other) = &relation.operand else { continue };
+            let linked = groups.iter()
+                .positions(|group| group.contains(&&relation.variable_name) || group.contains(&other))
+                .collect_vec();
+            let mut merged = vec![&relation.variable_name, other];
+            for &index in linked.iter().rev() {
+                merged.extend(groups.remove(index));
+            }
+            groups.push(merged.into_iter().unique().collect());
+        }
+        groups
     }
+
+    // sums over every weak ordering of the variables (which are equal, which are smaller) consistent with the relations
+    // the number of strictly increasing value sequences for the distinct values of that ordering
+    fn group_combinations(&self, group: &[&String]) -> Count {
+        let mut total = Count::new(0);
+        for ranks in (0..group.len()).map(|_| 0..group.len()).multi_cartesian_product() {
+            let levels = ranks.iter().max().unwrap() + 1;
+            if (0..levels).any(|level| !ranks.contains(&level)) {
+                continue;
+            }
+            let rank = |name: &String| ranks[group.iter().position(|&n| n == name).unwrap()] as i64;
+            let consistent = self.relations.iter()
+                .filter(|relation| group.contains(&&relation.variable_name))
+                .all(|relation| match &relation.operand {
+                    Operand::Variable(other) => relation.comparison.evaluate(&rank(&relation.variable_name), &rank(other)),
+                    Operand::Constant(_) => true
+                });
+            if !consistent {
+                continue;
+            }
+            let level_values = (0..levels)
+                .map(|level| group.iter().zip(&ranks)
+                    .filter(|(_, &r)| r == level)
+                    .fold(IntervalSet::new(i64::MIN, i64::MAX), |acc, (name, _)| acc.intersect(&self.variables[*name])))
+                .collect_vec();
+            total = total.add(&increasing_sequences(&level_values));
+        }
+        total
+    }
+}
+
+// number of ways to pick v0 < v1 < ... with every vi taken from values[i]
+fn increasing_sequences(values: &[IntervalSet]) -> Count {
+    let bounds = values.iter()
+        .flat_map(|set| set.intervals.iter().flat_map(|&(from, to)| [from as i128, to as i128 + 1]))
+        .sorted()
+        .dedup()
+        .collect_vec();
+    let mut placed = vec![Count::new(0); values.len() + 1];
+    placed[0] = Count::new(1);
+    for (&from, &to) in bounds.iter().tuple_windows() {
+        let length = (to - from) as u128;
+        let contains = |set: &IntervalSet| set.intervals.iter().any(|&(a, b)| a as i128 <= from && to - 1 <= b as i128);
+        let mut next = placed.clone();
+        for start in 0..values.len() {
+            if placed[start].is_zero() {
+                continue;
+            }
+            // consecutive levels start..start + taken all get their values from this segment
+            for taken in 1..=values.len() - start {
+                if !contains(&values[start + taken - 1]) {
+                    break;
+                }
+                next[start + taken] = next[start + taken].add(&placed[start].mul(&Count::binomial(length, taken)));
+            }
+        }
+        placed = next;
+    }
+    placed.pop().unwrap()
 }
 
+#[derive(Clone, PartialEq, Eq)]
 struct Transition {
-    condition: Condition,
+    condition: Option<Condition>,
     target: String,
 }
 
@@ -136,15 +545,25 @@ impl Transition {
     fn new(data: &str) -> Self {
         let (c, target) = data.split(":").collect_tuple().unwrap();
         Self {
-            condition: Condition::new(c),
+            condition: Some(Condition::new(c)),
             target: target.to_string(),
         }
     }
     fn is_valid(&self, query: &Query) -> bool {
-        self.condition.evaluate(query)
+        self.condition.as_ref().is_none_or(|c| c.evaluate(query))
+    }
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.condition {
+            Some(condition) => write!(f, "{}:{}", condition, self.target),
+            None => write!(f, "{}", self.target)
+        }
     }
 }
 
+#[derive(Clone, PartialEq, Eq)]
 struct Step {
     transitions: Vec<Transition>,
 }
@@ -160,7 +579,7 @@ impl Step {
             .map(|c| Transition::new(c))
             .collect();
         let fallback = condition_strings.last().unwrap().to_string();
-        transitions.push(Transition { condition: Condition::true_condition(), target: fallback });
+        transitions.push(Transition { condition: None, target: fallback });
         Self {
             transitions,
         }
@@ -174,8 +593,88 @@ impl Step {
         }
         panic!()
     }
+
+    // a conditional transition right before the fallback is redundant if both lead to the same place
+    fn drop_redundant_transitions(&mut self) {
+        while self.transitions.len() > 1 && self.transitions[self.transitions.len() - 2].target == self.transitions.last().unwrap().target {
+            self.transitions.remove(self.transitions.len() - 2);
+        }
+    }
+
+    fn redirect(&mut self, from: &str, to: &str) {
+        self.transitions.iter_mut()
+            .filter(|transition| transition.target == from)
+            .for_each(|transition| transition.target = to.to_string());
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{{}}}", self.transitions.iter().join(","))
+    }
+}
+
+struct Check {
+    transition: Transition,
+    taken: bool,
+    reason: String,
+}
+
+struct Visit {
+    step: String,
+    checks: Vec<Check>,
+}
+
+struct Explanation {
+    visits: Vec<Visit>,
+    outcome: String,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for visit in &self.visits {
+            writeln!(f, "{}", visit.step)?;
+            let width = visit.checks.iter().map(|check| check.transition.to_string().len()).max().unwrap_or(0);
+            for check in &visit.checks {
+                let verdict = if check.taken { "taken" } else { "skipped" };
+                writeln!(f, "  {:width$}  {}, {}", check.transition.to_string(), verdict, check.reason, width = width)?;
+            }
+        }
+        write!(f, "{}", self.outcome)
+    }
+}
+
+#[derive(Debug)]
+enum WorkflowError {
+    MissingStart,
+    UndefinedTarget { step: String, target: String },
+    Cycle { steps: Vec<String> },
+    Unreachable { step: String },
+}
+
+impl WorkflowError {
+    // unreachable steps are only dead weight, everything else breaks evaluation
+    fn is_fatal(&self) -> bool {
+        !matches!(self, WorkflowError::Unreachable { .. })
+    }
+}
+
+impl fmt::Display for WorkflowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkflowError::MissingStart =>
+                write!(f, "there is no workflow named in"),
+            WorkflowError::UndefinedTarget { step, target } =>
+                write!(f, "workflow {} sends parts to undefined workflow {}", step, target),
+            WorkflowError::Cycle { steps } =>
+                write!(f, "workflows form a cycle: {} -> {}", steps.join(" -> "), steps[0]),
+            WorkflowError::Unreachable { step } =>
+                write!(f, "workflow {} is unreachable from in", step),
+        }
+    }
 }
 
+#[derive(Clone)]
 struct Workflow {
     steps: HashMap<String, Step>,
 }
@@ -201,45 +700,282 @@ impl Workflow {
         current == "A"
     }
 
-    fn acceptance_chains(&self) -> Vec<ConditionsChain> {
-        let step = self.steps.get("in").unwrap();
-        let chains = self.expand_step(&vec![vec![]], &step);
-        let mut result = vec![];
-        for conditions in chains {
-            result.push(ConditionsChain { conditions });
+    // stops early with an outcome naming the problem if the part reaches an undefined step or goes round in a cycle
+    fn explain(&self, query: &Query) -> Explanation {
+        let mut visits: Vec<Visit> = vec![];
+        let mut current = "in".to_string();
+        let outcome = loop {
+            if current == "A" || current == "R" {
+                break current;
+            }
+            let Some(step) = self.steps.get(&current) else {
+                break format!("undefined step {}", current);
+            };
+            if let Some(start) = visits.iter().position(|visit| visit.step == current) {
+                let path = visits[start..].iter().map(|visit| visit.step.as_str()).join(" -> ");
+                break format!("cycle {} -> {}", path, current);
+            }
+            let mut checks = vec![];
+            for transition in &step.transitions {
+                let taken = transition.is_valid(query);
+                let reason = match &transition.condition {
+                    Some(condition) => condition.explain(query),
+                    None => "fallback".to_string()
+                };
+                checks.push(Check { transition: transition.clone(), taken, reason });
+                if taken {
+                    break;
+                }
+            }
+            let next = checks.last().unwrap().transition.target.clone();
+            visits.push(Visit { step: current, checks });
+            current = next;
+        };
+        Explanation {
+            visits,
+            outcome,
+        }
+    }
+
+    // every variable some condition of the workflow looks at
+    fn variables(&self) -> BTreeSet<String> {
+        self.steps.values()
+            .flat_map(|step| &step.transitions)
+            .filter_map(|transition| transition.condition.as_ref())
+            .flat_map(|condition| &condition.predicates)
+            .flat_map(|predicate| predicate.variables())
+            .cloned()
+            .collect()
+    }
+
+    fn accepted_regions(&self, domain: &Domain) -> Vec<Region> {
+        let mut accepted = vec![];
+        self.expand_step("in", Region::new(domain), domain, &mut accepted);
+        accepted
+    }
+
+    fn expand_step(&self, name: &str, region: Region, domain: &Domain, accepted: &mut Vec<Region>) {
+        if region.is_empty() || name == "R" {
+            return;
+        }
+        if name == "A" {
+            accepted.push(region);
+            return;
+        }
+        let mut remaining = vec![region];
+        for transition in &self.steps.get(name).unwrap().transitions {
+            match &transition.condition {
+                None => {
+                    remaining.into_iter().for_each(|region| self.expand_step(&transition.target, region, domain, accepted));
+                    return;
+                }
+                Some(condition) => {
+                    for region in &remaining {
+                        self.expand_step(&transition.target, region.matching(condition, domain), domain, accepted);
+                    }
+                    remaining = remaining.iter()
+                        .flat_map(|region| region.not_matching(condition, domain))
+                        .filter(|region| !region.is_empty())
+                        .collect();
+                }
+            }
         }
-        result
     }
 
-    fn expand_step(&self, paths: &Vec<Vec<Condition>>, step: &Step) -> Vec<Vec<Condition>> {
-        let mut result_paths = vec![];
-        let mut negated_conditions: Vec<Condition> = vec![];
-        for transition in &step.transitions {
-            let mut paths_with_negations = paths.clone();
-            for c in &negated_conditions {
-                for p in &mut paths_with_negations {
-                    p.push(c.clone())
+    fn accepted_combinations(&self, domain: &Domain) -> Count {
+        self.accepted_regions(domain)
+            .iter()
+            .fold(Count::new(0), |acc, region| acc.add(&region.combinations()))
+    }
+
+    // for every reachable step, whether each of its transitions sends any part of the domain onwards
+    fn firing_transitions(&self, domain: &Domain) -> HashMap<String, Vec<bool>> {
+        let mut firing = HashMap::new();
+        self.mark_firing("in", Region::new(domain), domain, &mut firing);
+        firing
+    }
+
+    fn mark_firing(&self, name: &str, region: Region, domain: &Domain, firing: &mut HashMap<String, Vec<bool>>) {
+        if region.is_empty() || name == "A" || name == "R" {
+            return;
+        }
+        let step = self.steps.get(name).unwrap();
+        firing.entry(name.to_string()).or_insert_with(|| vec![false; step.transitions.len()]);
+        let mut remaining = vec![region];
+        for (index, transition) in step.transitions.iter().enumerate() {
+            for region in &remaining {
+                let matched = match &transition.condition {
+                    None => region.clone(),
+                    Some(condition) => region.matching(condition, domain)
+                };
+                if !matched.is_empty() {
+                    firing.get_mut(name).unwrap()[index] = true;
+                    self.mark_firing(&transition.target, matched, domain, firing);
                 }
             }
-            result_paths.extend(self.expand_condition(&paths_with_negations, &transition.condition, &transition.target));
-            negated_conditions.push(transition.condition.negate())
+            remaining = match &transition.condition {
+                None => return,
+                Some(condition) => remaining.iter()
+                    .flat_map(|region| region.not_matching(condition, domain))
+                    .filter(|region| !region.is_empty())
+                    .collect()
+            };
+            if remaining.is_empty() {
+                return;
+            }
         }
-        result_paths
     }
 
-    fn expand_condition(&self, paths: &Vec<Vec<Condition>>, c: &Condition, target: &String) -> Vec<Vec<Condition>> {
-        let mut result_paths = paths.clone();
-        for p in &mut result_paths {
-            p.push(c.clone())
+    fn remove_dead_transitions(&mut self, domain: &Domain) {
+        let firing = self.firing_transitions(domain);
+        self.steps.retain(|name, _| firing.contains_key(name));
+        for (name, step) in self.steps.iter_mut() {
+            let mut transitions: Vec<Transition> = step.transitions.iter()
+                .zip(&firing[name])
+                .filter(|(_, &fires)| fires)
+                .map(|(transition, _)| transition.clone())
+                .collect();
+            // nothing gets past the last transition which fires, so it can be taken unconditionally
+            transitions.last_mut().unwrap().condition = None;
+            step.transitions = transitions;
+            step.drop_redundant_transitions();
+        }
+    }
+
+    fn redirect(&mut self, from: &str, to: &str) {
+        self.steps.remove(from);
+        self.steps.values_mut().for_each(|step| step.redirect(from, to));
+    }
+
+    // steps which unconditionally forward to another step are replaced by their target
+    fn inline_forwarding_steps(&mut self) {
+        while let Some((name, target)) = self.steps.iter()
+            .filter(|(name, step)| name.as_str() != "in" && step.transitions.len() == 1 && &step.transitions[0].target != *name)
+            .map(|(name, step)| (name.clone(), step.transitions[0].target.clone()))
+            .min() {
+            self.redirect(&name, &target);
+        }
+    }
+
+    fn merge_identical_steps(&mut self) {
+        loop {
+            let duplicate = self.steps.iter()
+                .into_group_map_by(|(_, step)| step.to_string())
+                .into_values()
+                .filter(|group| group.len() > 1)
+                .map(|group| {
+                    let names = group.iter().map(|(name, _)| name.to_string()).sorted_by_key(|name| (name != "in", name.clone())).collect_vec();
+                    (names[1].clone(), names[0].clone())
+                })
+                .min();
+            match duplicate {
+                Some((from, to)) => self.redirect(&from, &to),
+                None => break
+            }
         }
-        return if target == "A" {
-            result_paths
-        } else if target == "R" {
-            vec![]
-        } else {
-            let step = self.steps.get(target).unwrap();
-            self.expand_step(&result_paths, &step)
-        };
+    }
+
+    fn optimize(&self, domain: &Domain) -> Workflow {
+        let mut optimized = self.clone();
+        loop {
+            let before = optimized.render();
+            optimized.remove_dead_transitions(domain);
+            optimized.inline_forwarding_steps();
+            optimized.merge_identical_steps();
+            if optimized.render() == before {
+                return optimized;
+            }
+        }
+    }
+
+    // steps in the order they are first reached from `in`
+    fn reachable_steps(&self) -> Vec<String> {
+        let mut order = vec![];
+        let mut queue = VecDeque::from(["in".to_string()]);
+        while let Some(name) = queue.pop_front() {
+            if order.contains(&name) {
+                continue;
+            }
+            if let Some(step) = self.steps.get(&name) {
+                queue.extend(step.transitions.iter().map(|transition| transition.target.clone()));
+                order.push(name);
+            }
+        }
+        order
+    }
+
+    fn validate(&self) -> Vec<WorkflowError> {
+        let mut errors = vec![];
+        if !self.steps.contains_key("in") {
+            errors.push(WorkflowError::MissingStart);
+        }
+        for name in self.steps.keys().sorted() {
+            for transition in &self.steps[name].transitions {
+                let target = &transition.target;
+                if target != "A" && target != "R" && !self.steps.contains_key(target) {
+                    errors.push(WorkflowError::UndefinedTarget { step: name.clone(), target: target.clone() });
+                }
+            }
+        }
+        let mut graph = DiGraph::new();
+        let names = self.steps.keys().sorted().collect_vec();
+        let nodes: HashMap<&String, _> = names.iter().map(|&name| (name, graph.add_node(name))).collect();
+        for &name in &names {
+            for transition in &self.steps[name].transitions {
+                if let Some(&target) = nodes.get(&transition.target) {
+                    graph.add_edge(nodes[name], target, ());
+                }
+            }
+        }
+        let mut cycles = tarjan_scc(&graph).into_iter()
+            .filter(|component| component.len() > 1 || graph.contains_edge(component[0], component[0]))
+            .map(|component| self.cycle_through(component.iter().map(|&node| graph[node]).collect()))
+            .collect_vec();
+        cycles.sort();
+        errors.extend(cycles.into_iter().map(|steps| WorkflowError::Cycle { steps }));
+        if self.steps.contains_key("in") {
+            let reachable = self.reachable_steps();
+            errors.extend(names.iter()
+                .filter(|name| !reachable.contains(name))
+                .map(|name| WorkflowError::Unreachable { step: name.to_string() }));
+        }
+        errors
+    }
+
+    // one concrete loop inside a strongly connected group of steps, starting from its smallest name
+    fn cycle_through(&self, component: Vec<&String>) -> Vec<String> {
+        let start = component.iter().min().unwrap().to_string();
+        let mut path = vec![start.clone()];
+        let mut visited = vec![start.clone()];
+        loop {
+            let current = path.last().unwrap();
+            let targets = self.steps[current].transitions.iter()
+                .map(|transition| &transition.target)
+                .filter(|target| component.contains(target))
+                .collect_vec();
+            if targets.contains(&&start) {
+                return path;
+            }
+            // within a strongly connected component some unvisited step always leads back eventually
+            match targets.into_iter().find(|target| !visited.contains(target)) {
+                Some(next) => {
+                    visited.push(next.clone());
+                    path.push(next.clone());
+                }
+                None => {
+                    path.pop();
+                }
+            }
+        }
+    }
+
+    fn render(&self) -> String {
+        let reachable = self.reachable_steps();
+        let unreachable = self.steps.keys().filter(|name| !reachable.contains(name)).sorted().cloned().collect_vec();
+        reachable.iter()
+            .chain(unreachable.iter())
+            .map(|name| format!("{}{}", name, self.steps[name]))
+            .join("\n")
     }
 }
 
@@ -258,6 +994,10 @@ impl Query {
         }
     }
 
+    fn value(&self, variable: &str) -> i64 {
+        *self.variables.get(variable).unwrap_or(&0)
+    }
+
     fn query_value(&self) -> i64 {
         self.variables
             .values()
@@ -265,11 +1005,11 @@ impl Query {
     }
 }
 
-fn part2(workflow: &Workflow) -> i64 {
-    workflow.acceptance_chains()
-        .iter()
-        .map(|chain| chain.combinations())
-        .sum()
+// parts range over the variables the workflow checks and the ones the given parts are rated in
+fn part2(workflow: &Workflow, queries: &[Query]) -> Count {
+    let mut variables = workflow.variables();
+    variables.extend(queries.iter().flat_map(|query| query.variables.keys().cloned()));
+    workflow.accepted_combinations(&Domain::uniform(&variables, 1, 4000))
 }
 
 fn part1(workflow: &Workflow, queries: &Vec<Query>) -> i64 {
@@ -283,9 +1023,14 @@ pub(crate) fn solve() {
     let contents = fs::read_to_string("19.txt").unwrap();
     let (wkf, queries) = contents.split("\n\n").collect_tuple().unwrap();
     let workflow = Workflow::new(wkf);
+    let errors = workflow.validate();
+    errors.iter().for_each(|error| println!("{}", error));
+    if errors.iter().any(|error| error.is_fatal()) {
+        return;
+    }
     let queries = queries.split("\n")
         .map(|query| Query::new(query))
         .collect();
     println!("{}", part1(&workflow, &queries));
-    println!("{}", part2(&workflow));
+    println!("{}", part2(&workflow, &queries));
 }
\ No newline at end of file
@@ -1,6 +1,7 @@
 use std::collections::{HashMap, VecDeque};
-use std::{fs, vec};
+use std::{fmt, fs, vec};
 use itertools::Itertools;
+use crate::cycles::{cycle_index, earliest_common_step, walk_until_repeat, Occurrences};
 
 #[derive(PartialEq, Eq, Copy, Clone)]
 enum PulseType {
@@ -8,6 +9,15 @@ enum PulseType {
     Low,
 }
 
+impl fmt::Display for PulseType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PulseType::High => write!(f, "high"),
+            PulseType::Low => write!(f, "low")
+        }
+    }
+}
+
 #[derive(Clone)]
 struct Signal {
     pulse: PulseType,
@@ -19,11 +29,14 @@ trait Module {
 
     fn reset(&mut self) {}
 
-    fn triggered(&self) -> bool {
-        false
-    }
-
     fn inputs(&self) -> Vec<String> { vec![] }
+
+    fn is_conjunction(&self) -> bool { false }
+
+    // everything that influences future pulses, used to detect repeating behavior
+    fn state(&self) -> Vec<bool> { vec![] }
+
+    fn set_state(&mut self, _: &[bool]) {}
 }
 
 enum FlipFlopStatus {
@@ -55,12 +68,19 @@ impl Module for FlipFlop {
     fn reset(&mut self) {
         self.status = FlipFlopStatus::Off
     }
+
+    fn state(&self) -> Vec<bool> {
+        vec![matches!(self.status, FlipFlopStatus::On)]
+    }
+
+    fn set_state(&mut self, state: &[bool]) {
+        self.status = if state[0] { FlipFlopStatus::On } else { FlipFlopStatus::Off }
+    }
 }
 
 struct Conjunction {
     inputs: Vec<String>,
     history: HashMap<String, PulseType>,
-    triggered: bool,
 }
 
 impl Module for Conjunction {
@@ -71,23 +91,34 @@ impl Module for Conjunction {
             .all(|&x| x == PulseType::High) {
             Some(PulseType::Low)
         } else {
-            self.triggered = true;
             Some(PulseType::High)
         };
     }
 
     fn reset(&mut self) {
         self.history = HashMap::new();
-        self.triggered = false;
-    }
-
-    fn triggered(&self) -> bool {
-        self.triggered
     }
 
     fn inputs(&self) -> Vec<String> {
         self.inputs.clone()
     }
+
+    fn is_conjunction(&self) -> bool {
+        true
+    }
+
+    fn state(&self) -> Vec<bool> {
+        self.inputs.iter()
+            .map(|name| self.history.get(name) == Some(&PulseType::High))
+            .collect()
+    }
+
+    fn set_state(&mut self, state: &[bool]) {
+        self.history = self.inputs.iter()
+            .zip(state)
+            .map(|(name, &high)| (name.clone(), if high { PulseType::High } else { PulseType::Low }))
+            .collect();
+    }
 }
 
 struct Broadcaster {}
@@ -98,24 +129,44 @@ impl Module for Broadcaster {
     }
 }
 
-struct Output {
-    inputs: Vec<String>,
+struct OutgoingSignal {
+    signal: Signal,
+    destination: String,
 }
 
-impl Module for Output {
-    fn inputs(&self) -> Vec<String> {
-        self.inputs.clone()
+// a pulse as it is sent, in the order pulses are processed
+#[derive(Clone)]
+struct PulseEvent {
+    source: String,
+    pulse: PulseType,
+    destination: String,
+}
+
+impl fmt::Display for PulseEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -{}-> {}", self.source, self.pulse, self.destination)
     }
 }
 
-struct OutgoingSignal {
-    signal: Signal,
-    destination: String,
+#[derive(Default, Clone, Copy)]
+struct PulseCounter {
+    sent_low: u64,
+    sent_high: u64,
+    received_low: u64,
+    received_high: u64,
 }
 
+// an input of the conjunction feeding the target, with every module driving it
+type SubCircuit = (String, Vec<String>);
+
+// how many presses at which every sub-circuit fires are replayed before giving up on the decomposition
+const CANDIDATE_PRESSES: usize = 16;
+
 struct GreatMachine {
     modules: HashMap<String, Box<dyn Module>>,
     connections: HashMap<String, Vec<String>>,
+    counters: HashMap<String, PulseCounter>,
+    presses: u64,
 }
 
 impl GreatMachine {
@@ -144,16 +195,15 @@ impl GreatMachine {
                 module = Box::new(Conjunction {
                     inputs: Self::incoming_edges(&name, &connections),
                     history: Default::default(),
-                    triggered: false,
                 });
             }
             modules.insert(name.clone(), module);
         }
-        let output_inputs = Self::incoming_edges(&"rx".to_string(), &connections);
-        modules.insert("rx".to_string(), Box::new(Output { inputs: output_inputs }));
         Self {
             modules,
             connections,
+            counters: HashMap::new(),
+            presses: 0,
         }
     }
 
@@ -164,79 +214,215 @@ impl GreatMachine {
             .collect()
     }
 
-    fn press_button(&mut self) -> (i64, i64) {
+    fn press_button_with(&mut self, on_event: &mut impl FnMut(&PulseEvent)) {
+        self.presses += 1;
         let mut signals = VecDeque::new();
-        let mut highs = 0;
-        let mut lows = 1;
-        signals.push_back(OutgoingSignal { signal: Signal { pulse: PulseType::Low, source: "".to_string() }, destination: "broadcaster".to_string() });
-        while !signals.is_empty() {
-            let outgoing = signals.pop_front().unwrap();
-            if self.modules.contains_key(&outgoing.destination) {
-                let destination_module = self.modules.get_mut(&outgoing.destination).unwrap();
-                let new_pulse = destination_module.handle_signal(&outgoing.signal);
-                if new_pulse.is_some() {
-                    let pulse = new_pulse.unwrap();
+        signals.push_back(OutgoingSignal { signal: Signal { pulse: PulseType::Low, source: "button".to_string() }, destination: "broadcaster".to_string() });
+        while let Some(outgoing) = signals.pop_front() {
+            let event = PulseEvent {
+                source: outgoing.signal.source.clone(),
+                pulse: outgoing.signal.pulse,
+                destination: outgoing.destination.clone(),
+            };
+            self.count(&event);
+            on_event(&event);
+            if let Some(destination_module) = self.modules.get_mut(&outgoing.destination) {
+                if let Some(pulse) = destination_module.handle_signal(&outgoing.signal) {
                     for child in self.connections.get(&outgoing.destination).unwrap() {
-                        match pulse {
-                            PulseType::High => highs += 1,
-                            PulseType::Low => lows += 1,
-                        }
-                        let signal_to_send = Signal { pulse: pulse.clone(), source: outgoing.destination.clone() };
+                        let signal_to_send = Signal { pulse, source: outgoing.destination.clone() };
                         let signal_to_child = OutgoingSignal { signal: signal_to_send, destination: child.clone() };
                         signals.push_back(signal_to_child)
                     }
                 }
             }
         }
+    }
+
+    fn count(&mut self, event: &PulseEvent) {
+        let source = self.counters.entry(event.source.clone()).or_default();
+        match event.pulse {
+            PulseType::High => source.sent_high += 1,
+            PulseType::Low => source.sent_low += 1,
+        }
+        let destination = self.counters.entry(event.destination.clone()).or_default();
+        match event.pulse {
+            PulseType::High => destination.received_high += 1,
+            PulseType::Low => destination.received_low += 1,
+        }
+    }
+
+    fn press_button(&mut self) -> (i64, i64) {
+        let mut highs = 0;
+        let mut lows = 0;
+        self.press_button_with(&mut |event| match event.pulse {
+            PulseType::High => highs += 1,
+            PulseType::Low => lows += 1,
+        });
         (lows, highs)
     }
 
-    fn reset(&mut self) {
-        for m in self.modules.values_mut() {
-            m.reset();
+    fn press_button_logged(&mut self) -> Vec<PulseEvent> {
+        let mut events = vec![];
+        self.press_button_with(&mut |event| events.push(event.clone()));
+        events
+    }
+
+    // presses the button until some pulse satisfies the predicate, returning the number of presses since reset
+    fn run_until(&mut self, mut predicate: impl FnMut(&PulseEvent) -> bool, max_presses: u64) -> Option<u64> {
+        while self.presses < max_presses {
+            let mut fired = false;
+            self.press_button_with(&mut |event| fired |= predicate(event));
+            if fired {
+                return Some(self.presses);
+            }
         }
+        None
+    }
+
+    fn counter(&self, module: &str) -> PulseCounter {
+        self.counters.get(module).copied().unwrap_or_default()
+    }
+
+    fn state(&self, modules: &[String]) -> Vec<bool> {
+        modules.iter()
+            .flat_map(|name| self.modules[name].state())
+            .collect()
     }
-}
 
+    fn set_state(&mut self, modules: &[String], state: &[bool]) {
+        let mut rest = state;
+        for name in modules {
+            let module = self.modules.get_mut(name).unwrap();
+            let (own, others) = rest.split_at(module.state().len());
+            module.set_state(own);
+            rest = others;
+        }
+    }
 
-fn count_cycle(node: &str, machine: &mut GreatMachine) -> i64 {
-    machine.reset();
-    let mut presses = 0;
-    loop {
-        presses += 1;
-        machine.press_button();
-        let conjunction = machine.modules.get(node).unwrap();
-        if conjunction.triggered() {
-            break;
+    // modules which can send pulses to `name`, directly or indirectly, apart from the broadcaster
+    fn ancestors(&self, name: &str) -> Vec<String> {
+        let mut found = vec![name.to_string()];
+        let mut queue = VecDeque::from([name.to_string()]);
+        while let Some(current) = queue.pop_front() {
+            for parent in Self::incoming_edges(&current, &self.connections) {
+                if parent != "broadcaster" && !found.contains(&parent) {
+                    found.push(parent.clone());
+                    queue.push_back(parent);
+                }
+            }
         }
+        found.sort();
+        found
     }
-    presses
-}
 
-fn lcm(nums: &Vec<i64>) -> i64 {
-    nums.iter()
-        .map(|x| *x)
-        .reduce(|x, y| (x * y) / (gcd(x, y)))
-        .unwrap()
-}
+    // the conjunction feeding the target and, for each of its inputs, the independent sub-circuit driving it
+    fn counter_circuits(&self, target: &str) -> Option<(String, Vec<SubCircuit>)> {
+        let parents = Self::incoming_edges(&target.to_string(), &self.connections);
+        let conjunction = parents.first()?;
+        if parents.len() != 1 || !self.modules[conjunction].is_conjunction() {
+            return None;
+        }
+        let circuits = self.modules[conjunction].inputs().into_iter()
+            .map(|input| {
+                let circuit = self.ancestors(&input);
+                (input, circuit)
+            })
+            .collect_vec();
+        let independent = circuits.iter().all(|(_, circuit)| !circuit.contains(conjunction) && !circuit.iter().any(|name| name == target))
+            && circuits.iter().tuple_combinations().all(|((_, a), (_, b))| a.iter().all(|name| !b.contains(name)));
+        if independent {
+            Some((conjunction.clone(), circuits))
+        } else {
+            None
+        }
+    }
 
-fn gcd(a: i64, b: i64) -> i64 {
-    if b == 0 {
-        return a;
+    // number of presses after reset until the target first receives a low pulse
+    fn presses_until_low(&mut self, target: &str, max_presses: u64) -> Option<u64> {
+        self.reset();
+        let reached = |event: &PulseEvent| event.destination == target && event.pulse == PulseType::Low;
+        let Some((conjunction, circuits)) = self.counter_circuits(target) else {
+            return self.run_until(reached, max_presses);
+        };
+        let mut firings = vec![];
+        let mut walks = vec![];
+        let mut simulated = 0;
+        let mut first_reached = None;
+        let mut latched = false;
+        for (input, circuit) in &circuits {
+            self.reset();
+            // an independent sub-circuit's state after a press only depends on its state before it
+            let walk = walk_until_repeat((self.state(circuit), false), |_| {
+                let mut fired = false;
+                let mut last = None;
+                let mut done = false;
+                self.press_button_with(&mut |event| {
+                    if &event.source == input && event.destination == conjunction {
+                        fired |= event.pulse == PulseType::High;
+                        last = Some(event.pulse);
+                    }
+                    done |= reached(event);
+                });
+                // presses only combine independently if the conjunction forgets every high pulse by the end of each one
+                latched |= last == Some(PulseType::High);
+                if done && first_reached.is_none() {
+                    first_reached = Some(self.presses);
+                }
+                (self.state(circuit), fired)
+            }, max_presses as usize);
+            simulated = simulated.max(self.presses);
+            match walk {
+                Some((history, tail_length)) if !latched => {
+                    firings.push(Occurrences::new(&history, tail_length, |(_, fired)| *fired));
+                    walks.push((history.into_iter().map(|(state, _)| state).collect_vec(), tail_length));
+                }
+                _ => break
+            }
+        }
+        if first_reached.is_some() {
+            return first_reached;
+        }
+        if walks.len() == circuits.len() {
+            // every press up to `simulated` already ran on the whole machine without reaching the target
+            let mut from = simulated as usize + 1;
+            for _ in 0..CANDIDATE_PRESSES {
+                // the conjunction only sends a low pulse during a press in which all of its inputs send a high one
+                let press = earliest_common_step(&firings, from)?;
+                if self.reaches_on_press(target, &circuits, &walks, press) {
+                    return Some(press as u64);
+                }
+                from = press + 1;
+            }
+        }
+        self.reset();
+        self.run_until(reached, max_presses)
+    }
+
+    // replays a single press from the states the sub-circuits have before it, to check that the high pulses
+    // of all inputs actually overlap at the conjunction
+    fn reaches_on_press(&mut self, target: &str, circuits: &[SubCircuit], walks: &[(Vec<Vec<bool>>, usize)], press: usize) -> bool {
+        self.reset();
+        for ((_, circuit), (states, tail_length)) in circuits.iter().zip(walks) {
+            let index = cycle_index(*tail_length, states.len() - tail_length, press - 1);
+            self.set_state(circuit, &states[index]);
+        }
+        let mut reached = false;
+        self.press_button_with(&mut |event| reached |= event.destination == target && event.pulse == PulseType::Low);
+        reached
+    }
+
+    fn reset(&mut self) {
+        for m in self.modules.values_mut() {
+            m.reset();
+        }
+        self.counters.clear();
+        self.presses = 0;
     }
-    gcd(b, a % b)
 }
 
-fn part2(machine: &mut GreatMachine) -> i64 {
-    let rx = machine.modules.get("rx").unwrap();
-    let output_inputs = rx.inputs();
-    let last_conjunction = output_inputs.get(0).unwrap().clone();
-    let cycle_outputs = machine.modules.get(&last_conjunction).unwrap().inputs();
-    let cycles = cycle_outputs
-        .iter()
-        .map(|node| count_cycle(node, machine))
-        .collect();
-    lcm(&cycles)
+
+fn part2(machine: &mut GreatMachine) -> u64 {
+    machine.presses_until_low("rx", 1 << 24).unwrap()
 }
 
 fn part1(machine: &mut GreatMachine) -> i64 {
@@ -255,4 +441,100 @@ pub(crate) fn solve() {
     let machine = &mut GreatMachine::new(&contents);
     println!("{}", part1(machine));
     println!("{}", part2(machine));
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // small linear congruential generator, so the generated machines are the same on every run
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((self.0 >> 33) % n as u64) as usize
+        }
+    }
+
+    // the shape of the puzzle input: a binary counter of flip-flops started by the broadcaster, whose conjunction
+    // sends a low pulse and resets it every `period` presses
+    fn counter(c: usize, period: usize, outputs: &[String], lines: &mut Vec<String>) {
+        let bits = (usize::BITS - period.leading_zeros()) as usize;
+        for bit in 0..bits {
+            let mut targets = vec![];
+            if bit + 1 < bits {
+                targets.push(format!("f{}b{}", c, bit + 1));
+            }
+            if period >> bit & 1 == 1 {
+                targets.push(format!("k{}", c));
+            }
+            lines.push(format!("%f{}b{} -> {}", c, bit, targets.join(", ")));
+        }
+        let resets = (0..bits)
+            .filter(|&bit| bit == 0 || period >> bit & 1 == 0)
+            .map(|bit| format!("f{}b{}", c, bit));
+        lines.push(format!("&k{} -> {}", c, resets.chain(outputs.iter().cloned()).join(", ")));
+    }
+
+    fn machine(circuits: usize, lines: Vec<String>) -> String {
+        let starts = (0..circuits).map(|c| format!("f{}b0", c)).join(", ");
+        lines.into_iter()
+            .chain([format!("broadcaster -> {}", starts), "&hub -> rx".to_string()])
+            .join("\n")
+    }
+
+    // counters reporting to the conjunction feeding rx through an inverter, exactly like the puzzle input
+    fn counter_machine(periods: &[usize]) -> String {
+        let mut lines = vec![];
+        for (c, &period) in periods.iter().enumerate() {
+            counter(c, period, &[format!("n{}", c)], &mut lines);
+            lines.push(format!("&n{} -> hub", c));
+        }
+        machine(periods.len(), lines)
+    }
+
+    // counters reporting through a few random flip-flops and conjunctions, which delay their pulses or keep
+    // the conjunction feeding rx from ever seeing all of them high together; pulses only travel forwards
+    // through those modules, as a loop through a conjunction could keep a press from ever finishing
+    fn random_machine(rng: &mut Lcg) -> String {
+        let mut lines = vec![];
+        let circuits = 1 + rng.below(3);
+        for c in 0..circuits {
+            let size = rng.below(4);
+            let names = (0..size).map(|i| format!("d{}m{}", c, i)).chain(["hub".to_string()]).collect_vec();
+            counter(c, 3 + 2 * rng.below(7), &names[..1], &mut lines);
+            for i in 0..size {
+                let kind = if rng.below(3) == 0 { "%" } else { "&" };
+                let targets = [i + 1, i + 1 + rng.below(size - i)].map(|j| names[j].clone());
+                lines.push(format!("{}{} -> {}", kind, names[i], targets.iter().unique().join(", ")));
+            }
+        }
+        machine(circuits, lines)
+    }
+
+    fn brute_force(data: &str, max_presses: u64) -> Option<u64> {
+        let mut machine = GreatMachine::new(data);
+        machine.run_until(|event| event.destination == "rx" && event.pulse == PulseType::Low, max_presses)
+    }
+
+    #[test]
+    fn counters_match_brute_force() {
+        for periods in [vec![3], vec![5, 7], vec![11, 13, 3], vec![37, 41, 59], vec![9, 15, 21]] {
+            let data = counter_machine(&periods);
+            // the sub-circuits are walked for fewer presses than the answer, so it has to come from combining them
+            let predicted = GreatMachine::new(&data).presses_until_low("rx", 64);
+            assert_eq!(predicted, brute_force(&data, 1 << 20), "{}", data);
+        }
+    }
+
+    #[test]
+    fn random_circuits_match_brute_force() {
+        let mut rng = Lcg(20);
+        for _ in 0..100 {
+            let data = random_machine(&mut rng);
+            let predicted = GreatMachine::new(&data).presses_until_low("rx", 4096);
+            assert_eq!(predicted, brute_force(&data, 4096), "{}", data);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::fs;
 use itertools::Itertools;
+use crate::cycles::{earliest_common_step, walk_until_repeat, Occurrences};
 
 enum Direction {
     L,
@@ -64,28 +65,21 @@ impl Map {
     }
 }
 
-fn lcm(nums: &Vec<usize>) -> usize {
-    nums.iter()
-        .map(|x| *x)
-        .reduce(|x, y| (x * y) / (gcd(x, y)))
-        .unwrap()
-}
-
-fn gcd(a: usize, b: usize) -> usize {
-    if b == 0 {
-        return a;
-    }
-    gcd(b, a % b)
+// steps at which a ghost starting at `start` stands on a node ending with Z
+fn ghost_arrivals(map: &Map, start: &String) -> Occurrences {
+    let next = |(node, position): &(String, usize)| (map.next_node(node, *position).clone(), (position + 1) % map.directions.len());
+    let (history, tail_length) = walk_until_repeat((start.clone(), 0), next, usize::MAX).unwrap();
+    Occurrences::new(&history, tail_length, |(node, _)| node.ends_with("Z"))
 }
 
 fn part2(map: &Map) -> usize {
     let starting_positions: Vec<&String> = map.nodes.keys()
         .filter(|&x| x.ends_with("A"))
         .collect();
-    let cycles = starting_positions.iter()
-        .map(|start| step_count(map, start, &|current| current.ends_with("Z")))
-        .collect();
-    lcm(&cycles)
+    let arrivals = starting_positions.iter()
+        .map(|start| ghost_arrivals(map, start))
+        .collect_vec();
+    earliest_common_step(&arrivals, 0).unwrap()
 }
 
 fn step_count(map: &Map, start: &String, end_condition: &impl Fn(&String) -> bool) -> usize {
@@ -23,6 +23,8 @@ mod day22;
 mod day23;
 mod day24;
 mod day25;
+mod cycles;
+mod polygon;
 
 fn main() {
     // day1::solve();
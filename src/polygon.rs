@@ -0,0 +1,142 @@
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(crate) enum Orientation {
+    Clockwise,
+    CounterClockwise,
+    Degenerate,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(crate) struct Intersection {
+    pub(crate) first_edge: usize,
+    pub(crate) second_edge: usize,
+}
+
+type Edge = ((i64, i64), (i64, i64));
+
+// closed polygon with integer vertices, the last vertex connects back to the first one
+pub(crate) struct Polygon {
+    pub(crate) vertices: Vec<(i64, i64)>,
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        return a;
+    }
+    gcd(b, a % b)
+}
+
+fn cross(o: (i64, i64), a: (i64, i64), b: (i64, i64)) -> i128 {
+    (a.0 - o.0) as i128 * (b.1 - o.1) as i128 - (a.1 - o.1) as i128 * (b.0 - o.0) as i128
+}
+
+fn on_segment(p: (i64, i64), a: (i64, i64), b: (i64, i64)) -> bool {
+    cross(a, b, p) == 0 && p.0 >= a.0.min(b.0) && p.0 <= a.0.max(b.0) && p.1 >= a.1.min(b.1) && p.1 <= a.1.max(b.1)
+}
+
+fn segments_intersect(a: ((i64, i64), (i64, i64)), b: ((i64, i64), (i64, i64))) -> bool {
+    let d1 = cross(b.0, b.1, a.0).signum();
+    let d2 = cross(b.0, b.1, a.1).signum();
+    let d3 = cross(a.0, a.1, b.0).signum();
+    let d4 = cross(a.0, a.1, b.1).signum();
+    if d1 * d2 < 0 && d3 * d4 < 0 {
+        return true;
+    }
+    on_segment(a.0, b.0, b.1) || on_segment(a.1, b.0, b.1) || on_segment(b.0, a.0, a.1) || on_segment(b.1, a.0, a.1)
+}
+
+impl Polygon {
+    pub(crate) fn new(vertices: Vec<(i64, i64)>) -> Self {
+        let mut vertices = vertices;
+        if vertices.len() > 1 && vertices.first() == vertices.last() {
+            vertices.pop();
+        }
+        Self {
+            vertices
+        }
+    }
+
+    pub(crate) fn edges(&self) -> impl Iterator<Item=((i64, i64), (i64, i64))> + '_ {
+        (0..self.vertices.len())
+            .map(|i| (self.vertices[i], self.vertices[(i + 1) % self.vertices.len()]))
+    }
+
+    // shoelace formula, positive for counter-clockwise vertices when y grows upwards
+    pub(crate) fn doubled_signed_area(&self) -> i64 {
+        self.edges()
+            .map(|(c1, c2)| (c1.0 * c2.1) - (c1.1 * c2.0))
+            .sum()
+    }
+
+    pub(crate) fn doubled_area(&self) -> i64 {
+        self.doubled_signed_area().abs()
+    }
+
+    pub(crate) fn orientation(&self) -> Orientation {
+        match self.doubled_signed_area().signum() {
+            1 => Orientation::CounterClockwise,
+            -1 => Orientation::Clockwise,
+            _ => Orientation::Degenerate
+        }
+    }
+
+    pub(crate) fn perimeter(&self) -> f64 {
+        self.edges()
+            .map(|(c1, c2)| (((c2.0 - c1.0) as f64).powi(2) + ((c2.1 - c1.1) as f64).powi(2)).sqrt())
+            .sum()
+    }
+
+    pub(crate) fn is_rectilinear(&self) -> bool {
+        self.edges().all(|(c1, c2)| c1.0 == c2.0 || c1.1 == c2.1)
+    }
+
+    pub(crate) fn boundary_points(&self) -> i64 {
+        self.edges()
+            .map(|(c1, c2)| gcd((c2.0 - c1.0).abs(), (c2.1 - c1.1).abs()))
+            .sum()
+    }
+
+    // Pick's theorem: A = I + B/2 - 1
+    pub(crate) fn interior_points(&self) -> i64 {
+        (self.doubled_area() - self.boundary_points() + 2) / 2
+    }
+
+    pub(crate) fn enclosed_points(&self) -> i64 {
+        self.interior_points() + self.boundary_points()
+    }
+
+    // first pair of edges which cross, touch, or overlap other than neighbours sharing their common vertex
+    pub(crate) fn self_intersection(&self) -> Option<Intersection> {
+        let edges: Vec<_> = self.edges().collect();
+        first_intersection(&edges, true)
+    }
+}
+
+// the same for an open path through the vertices, whose first and last edges are no neighbours
+pub(crate) fn path_self_intersection(vertices: &[(i64, i64)]) -> Option<Intersection> {
+    let edges: Vec<_> = vertices.windows(2).map(|pair| (pair[0], pair[1])).collect();
+    first_intersection(&edges, false)
+}
+
+fn first_intersection(edges: &[Edge], closed: bool) -> Option<Intersection> {
+    let n = edges.len();
+    for second_edge in 1..n {
+        for first_edge in 0..second_edge {
+            let adjacent = second_edge == first_edge + 1 || (closed && first_edge == 0 && second_edge == n - 1);
+            let intersects = if adjacent {
+                let (shared, a, b) = if second_edge == first_edge + 1 {
+                    (edges[second_edge].0, edges[first_edge].0, edges[second_edge].1)
+                } else {
+                    (edges[first_edge].0, edges[first_edge].1, edges[second_edge].0)
+                };
+                // neighbours only overlap when the path folds back onto itself, which includes a loop of two edges
+                cross(shared, a, b) == 0 && ((a.0 - shared.0) * (b.0 - shared.0) + (a.1 - shared.1) * (b.1 - shared.1)) > 0
+            } else {
+                segments_intersect(edges[first_edge], edges[second_edge])
+            };
+            if intersects {
+                return Some(Intersection { first_edge, second_edge });
+            }
+        }
+    }
+    None
+}